@@ -0,0 +1,9 @@
+//! Wire format shared by AIMC firmware and hosts.
+//!
+//! Hosts encode `AIMCMessage`s and decode `Status` reports and parameter replies; devices do the
+//! reverse with the same definitions. Builds without std unless the `std` feature is enabled.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+mod message;
+mod parameters;
+pub use message::*;
+pub use parameters::*;
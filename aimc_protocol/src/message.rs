@@ -0,0 +1,637 @@
+use crate::{ControlMode, Parameter};
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+#[cfg(feature = "serde_support")]
+use serde::{Deserialize, Serialize};
+
+// Currently, all AIMCs are little endian
+pub(crate) type DeviceEndian = LittleEndian;
+
+// Byte slice that make up the content of the message
+const CONTENT_BYTE_SLICE: core::ops::Range<usize> = 1..5;
+
+/// Conditions reported by a device alongside its status
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct StatusFlags(u32);
+
+impl StatusFlags {
+    /// Switch at the minimum end of travel is pressed. This is the homing switch.
+    pub const LIMIT_MIN: Self = Self(1 << 0);
+    /// Switch at the maximum end of travel is pressed
+    pub const LIMIT_MAX: Self = Self(1 << 1);
+    pub const ENABLED: Self = Self(1 << 2);
+    pub const HOMING: Self = Self(1 << 3);
+    /// The device has disabled itself, such as when its watchdog expired
+    pub const FAULT: Self = Self(1 << 4);
+
+    const NAMED: [(Self, &'static str); 5] = [
+        (Self::LIMIT_MIN, "limit_min"),
+        (Self::LIMIT_MAX, "limit_max"),
+        (Self::ENABLED, "enabled"),
+        (Self::HOMING, "homing"),
+        (Self::FAULT, "fault"),
+    ];
+
+    // Control mode occupies two bits, with zero meaning unreported
+    const MODE_SHIFT: u32 = 8;
+    const MODE_MASK: u32 = 0b11 << Self::MODE_SHIFT;
+    const MODES: [ControlMode; 3] = [ControlMode::PWM, ControlMode::PID, ControlMode::Pneumatic];
+
+    /// Set in every flags word, telling it apart from the `f32` limit switch reading sent by
+    /// older firmware in the same place. That reading is never negative, so never has it set.
+    const WORD_MARKER: u32 = 1 << 31;
+    /// Older firmware's reading for a pressed switch, 1.0
+    const LEGACY_PRESSED: u32 = 0x3F80_0000;
+    const KNOWN_BITS: u32 = 0b1_1111 | Self::MODE_MASK;
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Flags from raw bits, dropping any this version does not know
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Self(bits & Self::KNOWN_BITS)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+
+    /// Whether either limit switch is pressed
+    pub fn at_limit(self) -> bool {
+        self.0 & (Self::LIMIT_MIN.0 | Self::LIMIT_MAX.0) != 0
+    }
+
+    /// Control mode, if the device reported one
+    pub fn mode(self) -> Option<ControlMode> {
+        let index = (self.0 & Self::MODE_MASK) >> Self::MODE_SHIFT;
+        Self::MODES.get(index.checked_sub(1)? as usize).copied()
+    }
+
+    pub fn with_mode(self, mode: ControlMode) -> Self {
+        let index = Self::MODES.iter().position(|m| *m == mode).unwrap() as u32 + 1;
+        Self(self.0 & !Self::MODE_MASK | index << Self::MODE_SHIFT)
+    }
+
+    /// Encode as the last word of a status
+    pub fn into_word(self) -> u32 {
+        Self::WORD_MARKER | self.0
+    }
+
+    /// Decode the last word of a status, in either the flags layout or the older `f32` limit
+    /// switch reading of 0.0 or 1.0. Flags added by newer firmware are dropped, keeping the rest;
+    /// words that fit neither layout are rejected.
+    pub fn from_word(word: u32) -> Option<Self> {
+        if word & Self::WORD_MARKER != 0 {
+            return Some(Self::from_bits_truncate(word));
+        }
+        match word {
+            0 => Some(Self::empty()),
+            Self::LEGACY_PRESSED => Some(Self::LIMIT_MIN),
+            _ => None,
+        }
+    }
+}
+
+impl core::ops::BitOr for StatusFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for StatusFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        for (flag, name) in Self::NAMED.iter() {
+            if self.contains(*flag) {
+                write!(f, "{}{}", separator, name)?;
+                separator = "|";
+            }
+        }
+        if let Some(mode) = self.mode() {
+            write!(f, "{}mode={:?}", separator, mode)?;
+            separator = "|";
+        }
+        if separator.is_empty() {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for StatusFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StatusFlags({})", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub encoder: f32,
+    pub target: f32,
+    pub pid_out: f32,
+    pub flags: StatusFlags,
+}
+
+impl Status {
+    /// Decode a status. A flags word that fits no known layout decodes as empty.
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self {
+            encoder: DeviceEndian::read_f32(&bytes[0..4]),
+            target: DeviceEndian::read_f32(&bytes[4..8]),
+            pid_out: DeviceEndian::read_f32(&bytes[8..12]),
+            flags: StatusFlags::from_word(DeviceEndian::read_u32(&bytes[12..16]))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Convert this status into the bytes a device would report.
+    pub fn into_bytes(self) -> [u8; 16] {
+        let mut buffer = [0u8; 16];
+        DeviceEndian::write_f32(&mut buffer[0..4], self.encoder);
+        DeviceEndian::write_f32(&mut buffer[4..8], self.target);
+        DeviceEndian::write_f32(&mut buffer[8..12], self.pid_out);
+        DeviceEndian::write_u32(&mut buffer[12..16], self.flags.into_word());
+        buffer
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "encoder {}, target {}, pid_out {}, flags {}",
+            self.encoder, self.target, self.pid_out, self.flags
+        )
+    }
+}
+
+/// Create a buffer and only set the opcode byte
+fn get_bytes_op(operation: u8) -> [u8; 5] {
+    let mut buffer = [0u8; 5];
+    buffer[0] = operation;
+    buffer
+}
+
+// Message translation functions
+fn get_bytes_u32(operation: u8, data: u32) -> [u8; 5] {
+    let mut buffer = get_bytes_op(operation);
+    DeviceEndian::write_u32(&mut buffer[CONTENT_BYTE_SLICE], data);
+    buffer
+}
+
+fn get_bytes_i32(operation: u8, data: i32) -> [u8; 5] {
+    let mut buffer = get_bytes_op(operation);
+    DeviceEndian::write_i32(&mut buffer[CONTENT_BYTE_SLICE], data);
+    buffer
+}
+
+fn get_bytes_f32(operation: u8, data: f32) -> [u8; 5] {
+    let mut buffer = get_bytes_op(operation);
+    DeviceEndian::write_f32(&mut buffer[CONTENT_BYTE_SLICE], data);
+    buffer
+}
+
+fn get_bytes_bool(operation: u8, data: bool) -> [u8; 5] {
+    get_bytes_u32(operation, if data { 1 } else { 0 })
+}
+
+// Message decoding functions
+fn read_bool(bytes: &[u8; 5]) -> Result<bool, DecodeError> {
+    match DeviceEndian::read_u32(&bytes[CONTENT_BYTE_SLICE]) {
+        0 => Ok(false),
+        1 => Ok(true),
+        value => Err(DecodeError::InvalidBool {
+            opcode: bytes[0],
+            value,
+        }),
+    }
+}
+
+fn read_f32(bytes: &[u8; 5]) -> f32 {
+    DeviceEndian::read_f32(&bytes[CONTENT_BYTE_SLICE])
+}
+
+/// Reasons a message could not be decoded from its bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeError {
+    /// Opcode is not assigned to any message. This includes the reserved opcode 0.
+    UnknownOpcode(u8),
+    /// A boolean payload held something other than 0 or 1
+    InvalidBool { opcode: u8, value: u32 },
+    /// A PWM limit payload did not fit in a byte
+    InvalidPwm(u32),
+    /// A framed message or status failed its CRC check
+    Checksum { expected: u8, actual: u8 },
+    /// Parameter id is not assigned to any parameter
+    UnknownParameter(u32),
+    /// A parameter reply held a value the parameter cannot take
+    InvalidParameterValue { parameter: u8, value: u32 },
+    /// A parameter reply was for a different parameter than requested
+    UnexpectedParameter { expected: u8, actual: u8 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(opcode) => write!(f, "Unknown opcode {}", opcode),
+            DecodeError::InvalidBool { opcode, value } => {
+                write!(f, "Invalid boolean {} for opcode {}", value, opcode)
+            }
+            DecodeError::InvalidPwm(value) => write!(f, "PWM limit {} is above 255", value),
+            DecodeError::Checksum { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {:#04x}, got {:#04x}",
+                expected, actual
+            ),
+            DecodeError::UnknownParameter(id) => write!(f, "Unknown parameter {}", id),
+            DecodeError::InvalidParameterValue { parameter, value } => {
+                write!(f, "Invalid value {} for parameter {}", value, parameter)
+            }
+            DecodeError::UnexpectedParameter { expected, actual } => write!(
+                f,
+                "Reply was for parameter {}, expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// Single communication from host to device
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum AIMCMessage {
+    Enable(bool),
+    SetTarget(f32),
+    Reset,
+    ModePWM,
+    ModePID,
+    ModePneumatic,
+    SetKp(f32),
+    SetKi(f32),
+    SetKd(f32),
+    Home(i32),
+    LimitPwm(u8),
+    LimitTargetMin(f32),
+    LimitTargetMax(f32),
+    EncoderPolarity(bool),
+    ReadParameter(Parameter),
+    /// Proof of life from the host. Any message feeds the watchdog; this one does nothing else.
+    Heartbeat,
+    /// Disable the device if nothing is heard from the host for this many milliseconds, or
+    /// never if zero
+    SetWatchdogTimeout(u32),
+    /// Reset into the I2C bootloader, which then answers at its own address
+    EnterBootloader,
+    /// Commit the persisted parameters to EEPROM, from which they are loaded at power-on
+    SaveConfig,
+    /// Replace the persisted parameters with those saved in EEPROM
+    LoadConfig,
+}
+
+impl AIMCMessage {
+    /// Convert this message into device-dependent bytes.
+    /// Note that values start at 1 as 0 may signify an error.
+    pub fn into_bytes(self) -> [u8; 5] {
+        match self {
+            AIMCMessage::Enable(value) => get_bytes_bool(1, value),
+            AIMCMessage::SetTarget(value) => get_bytes_f32(2, value),
+            AIMCMessage::Reset => get_bytes_op(3),
+            AIMCMessage::ModePWM => get_bytes_op(4),
+            AIMCMessage::ModePID => get_bytes_op(5),
+            AIMCMessage::ModePneumatic => get_bytes_op(6),
+            AIMCMessage::SetKp(value) => get_bytes_f32(7, value),
+            AIMCMessage::SetKi(value) => get_bytes_f32(8, value),
+            AIMCMessage::SetKd(value) => get_bytes_f32(9, value),
+            AIMCMessage::Home(value) => get_bytes_i32(10, value),
+            AIMCMessage::LimitPwm(value) => get_bytes_u32(11, u32::from(value)),
+            AIMCMessage::LimitTargetMin(value) => get_bytes_f32(12, value),
+            AIMCMessage::LimitTargetMax(value) => get_bytes_f32(13, value),
+            AIMCMessage::EncoderPolarity(value) => get_bytes_u32(14, u32::from(value)),
+            AIMCMessage::ReadParameter(parameter) => get_bytes_u32(15, parameter as u32),
+            AIMCMessage::Heartbeat => get_bytes_op(16),
+            AIMCMessage::SetWatchdogTimeout(value) => get_bytes_u32(17, value),
+            AIMCMessage::EnterBootloader => get_bytes_op(18),
+            AIMCMessage::SaveConfig => get_bytes_op(19),
+            AIMCMessage::LoadConfig => get_bytes_op(20),
+        }
+    }
+
+    /// Decode a message from device-dependent bytes. Inverse of `into_bytes`.
+    pub fn from_bytes(bytes: [u8; 5]) -> Result<Self, DecodeError> {
+        Ok(match bytes[0] {
+            1 => AIMCMessage::Enable(read_bool(&bytes)?),
+            2 => AIMCMessage::SetTarget(read_f32(&bytes)),
+            3 => AIMCMessage::Reset,
+            4 => AIMCMessage::ModePWM,
+            5 => AIMCMessage::ModePID,
+            6 => AIMCMessage::ModePneumatic,
+            7 => AIMCMessage::SetKp(read_f32(&bytes)),
+            8 => AIMCMessage::SetKi(read_f32(&bytes)),
+            9 => AIMCMessage::SetKd(read_f32(&bytes)),
+            10 => AIMCMessage::Home(DeviceEndian::read_i32(&bytes[CONTENT_BYTE_SLICE])),
+            11 => {
+                let value = DeviceEndian::read_u32(&bytes[CONTENT_BYTE_SLICE]);
+                if value > u32::from(u8::MAX) {
+                    return Err(DecodeError::InvalidPwm(value));
+                }
+                AIMCMessage::LimitPwm(value as u8)
+            }
+            12 => AIMCMessage::LimitTargetMin(read_f32(&bytes)),
+            13 => AIMCMessage::LimitTargetMax(read_f32(&bytes)),
+            14 => AIMCMessage::EncoderPolarity(read_bool(&bytes)?),
+            15 => AIMCMessage::ReadParameter(Parameter::from_id(DeviceEndian::read_u32(
+                &bytes[CONTENT_BYTE_SLICE],
+            ))?),
+            16 => AIMCMessage::Heartbeat,
+            17 => {
+                AIMCMessage::SetWatchdogTimeout(DeviceEndian::read_u32(&bytes[CONTENT_BYTE_SLICE]))
+            }
+            18 => AIMCMessage::EnterBootloader,
+            19 => AIMCMessage::SaveConfig,
+            20 => AIMCMessage::LoadConfig,
+            opcode => return Err(DecodeError::UnknownOpcode(opcode)),
+        })
+    }
+}
+
+/// Wire format spoken by a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum ProtocolVersion {
+    /// Opcode and payload only. Status is trusted as received.
+    #[default]
+    Bare,
+    /// Commands and status carry a rolling sequence number and a CRC-8.
+    Framed,
+}
+
+/// Length of a framed message: sequence, opcode, payload and CRC
+pub const FRAMED_MESSAGE_LEN: usize = 7;
+
+/// Length of a framed status: status, echoed sequence and CRC
+pub const FRAMED_STATUS_LEN: usize = 18;
+
+/// CRC-8 with polynomial 0x07 and zero initial value, as used by SMBus PEC
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+pub(crate) fn check_crc(bytes: &[u8]) -> Result<(), DecodeError> {
+    let (content, crc) = bytes.split_at(bytes.len() - 1);
+    let expected = crc8(content);
+    if expected != crc[0] {
+        return Err(DecodeError::Checksum {
+            expected,
+            actual: crc[0],
+        });
+    }
+    Ok(())
+}
+
+impl AIMCMessage {
+    /// Convert this message into a frame tagged with the sequence number.
+    pub fn into_framed_bytes(self, sequence: u8) -> [u8; FRAMED_MESSAGE_LEN] {
+        let mut buffer = [0u8; FRAMED_MESSAGE_LEN];
+        buffer[0] = sequence;
+        buffer[1..6].copy_from_slice(&self.into_bytes());
+        buffer[6] = crc8(&buffer[..6]);
+        buffer
+    }
+
+    /// Decode a framed message, returning its sequence number and content.
+    pub fn from_framed_bytes(bytes: [u8; FRAMED_MESSAGE_LEN]) -> Result<(u8, Self), DecodeError> {
+        check_crc(&bytes)?;
+        let mut message = [0u8; 5];
+        message.copy_from_slice(&bytes[1..6]);
+        Ok((bytes[0], Self::from_bytes(message)?))
+    }
+}
+
+impl Status {
+    /// Convert this status into a frame echoing the sequence number of the last accepted message.
+    pub fn into_framed_bytes(self, sequence: u8) -> [u8; FRAMED_STATUS_LEN] {
+        let mut buffer = [0u8; FRAMED_STATUS_LEN];
+        buffer[..16].copy_from_slice(&self.into_bytes());
+        buffer[16] = sequence;
+        buffer[17] = crc8(&buffer[..17]);
+        buffer
+    }
+
+    /// Decode a framed status, returning the echoed sequence number and content.
+    pub fn from_framed_bytes(bytes: [u8; FRAMED_STATUS_LEN]) -> Result<(u8, Self), DecodeError> {
+        check_crc(&bytes)?;
+        let mut status = [0u8; 16];
+        status.copy_from_slice(&bytes[..16]);
+        Ok((bytes[16], Self::from_bytes(status)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn any_message() -> impl Strategy<Value = AIMCMessage> {
+        prop_oneof![
+            any::<bool>().prop_map(AIMCMessage::Enable),
+            any::<f32>().prop_map(AIMCMessage::SetTarget),
+            Just(AIMCMessage::Reset),
+            Just(AIMCMessage::ModePWM),
+            Just(AIMCMessage::ModePID),
+            Just(AIMCMessage::ModePneumatic),
+            any::<f32>().prop_map(AIMCMessage::SetKp),
+            any::<f32>().prop_map(AIMCMessage::SetKi),
+            any::<f32>().prop_map(AIMCMessage::SetKd),
+            any::<i32>().prop_map(AIMCMessage::Home),
+            any::<u8>().prop_map(AIMCMessage::LimitPwm),
+            any::<f32>().prop_map(AIMCMessage::LimitTargetMin),
+            any::<f32>().prop_map(AIMCMessage::LimitTargetMax),
+            any::<bool>().prop_map(AIMCMessage::EncoderPolarity),
+            proptest::sample::select(&Parameter::ALL[..]).prop_map(AIMCMessage::ReadParameter),
+            Just(AIMCMessage::Heartbeat),
+            any::<u32>().prop_map(AIMCMessage::SetWatchdogTimeout),
+            Just(AIMCMessage::EnterBootloader),
+            Just(AIMCMessage::SaveConfig),
+            Just(AIMCMessage::LoadConfig),
+        ]
+    }
+
+    proptest! {
+        // Compared by bytes, since NaN payloads are not equal to themselves
+        #[test]
+        fn test_round_trip(message in any_message()) {
+            let bytes = message.into_bytes();
+            let decoded = AIMCMessage::from_bytes(bytes).unwrap();
+            prop_assert_eq!(decoded.into_bytes(), bytes);
+            prop_assert_eq!(
+                std::mem::discriminant(&decoded),
+                std::mem::discriminant(&message)
+            );
+        }
+
+        #[test]
+        fn test_decode_never_panics(bytes in any::<[u8; 5]>()) {
+            if let Ok(message) = AIMCMessage::from_bytes(bytes) {
+                prop_assert_eq!(message.into_bytes()[0], bytes[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_crc8() {
+        // Check value for CRC-8/SMBUS
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn test_framed_round_trip() {
+        let frame = AIMCMessage::SetTarget(2.0).into_framed_bytes(42);
+        let (sequence, message) = AIMCMessage::from_framed_bytes(frame).unwrap();
+        assert_eq!(sequence, 42);
+        assert_eq!(
+            message.into_bytes(),
+            AIMCMessage::SetTarget(2.0).into_bytes()
+        );
+
+        let status = Status {
+            encoder: 1.0,
+            target: 2.0,
+            pid_out: 3.0,
+            flags: StatusFlags::ENABLED,
+        };
+        let (sequence, decoded) = Status::from_framed_bytes(status.into_framed_bytes(7)).unwrap();
+        assert_eq!(sequence, 7);
+        assert_eq!(decoded.into_bytes(), status.into_bytes());
+    }
+
+    proptest! {
+        #[test]
+        fn test_framed_detects_bit_flips(
+            message in any_message(),
+            sequence in any::<u8>(),
+            bit in 0..(FRAMED_MESSAGE_LEN * 8),
+        ) {
+            let mut frame = message.into_framed_bytes(sequence);
+            frame[bit / 8] ^= 1 << (bit % 8);
+            let checksum_failed = matches!(
+                AIMCMessage::from_framed_bytes(frame),
+                Err(DecodeError::Checksum { .. })
+            );
+            prop_assert!(checksum_failed);
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            AIMCMessage::from_bytes([0, 0, 0, 0, 0]).unwrap_err(),
+            DecodeError::UnknownOpcode(0)
+        );
+        assert_eq!(
+            AIMCMessage::from_bytes([21, 0, 0, 0, 0]).unwrap_err(),
+            DecodeError::UnknownOpcode(21)
+        );
+        assert_eq!(
+            AIMCMessage::from_bytes([15, 0, 0, 0, 0]).unwrap_err(),
+            DecodeError::UnknownParameter(0)
+        );
+        assert_eq!(
+            AIMCMessage::from_bytes([1, 2, 0, 0, 0]).unwrap_err(),
+            DecodeError::InvalidBool {
+                opcode: 1,
+                value: 2
+            }
+        );
+        assert_eq!(
+            AIMCMessage::from_bytes([14, 0, 0, 0, 1]).unwrap_err(),
+            DecodeError::InvalidBool {
+                opcode: 14,
+                value: 1 << 24
+            }
+        );
+        assert_eq!(
+            AIMCMessage::from_bytes([11, 0, 1, 0, 0]).unwrap_err(),
+            DecodeError::InvalidPwm(256)
+        );
+    }
+
+    #[test]
+    fn test_status_flags() {
+        let flags = (StatusFlags::ENABLED | StatusFlags::LIMIT_MAX).with_mode(ControlMode::PID);
+        assert_eq!(flags.mode(), Some(ControlMode::PID));
+        assert!(flags.at_limit());
+        assert_eq!(flags.to_string(), "limit_max|enabled|mode=PID");
+        assert_eq!(StatusFlags::from_word(flags.into_word()), Some(flags));
+        assert_eq!(StatusFlags::empty().to_string(), "none");
+
+        // Older firmware reports the limit switch as an f32 in the same place
+        assert_eq!(
+            StatusFlags::from_word(1.0f32.to_bits()),
+            Some(StatusFlags::LIMIT_MIN)
+        );
+        assert_eq!(
+            StatusFlags::from_word(0.0f32.to_bits()),
+            Some(StatusFlags::empty())
+        );
+        assert_eq!(StatusFlags::from_word(0.5f32.to_bits()), None);
+
+        // Flags from newer firmware do not hide the ones we know
+        let word = StatusFlags::FAULT.into_word() | StatusFlags::LIMIT_MIN.bits() | 1 << 20;
+        assert_eq!(
+            StatusFlags::from_word(word),
+            Some(StatusFlags::LIMIT_MIN | StatusFlags::FAULT)
+        );
+        let mut bytes = Status {
+            encoder: 0.0,
+            target: 0.0,
+            pid_out: 0.0,
+            flags: StatusFlags::empty(),
+        }
+        .into_bytes();
+        bytes[12..].copy_from_slice(&word.to_le_bytes());
+        assert_eq!(
+            Status::from_bytes(bytes).flags,
+            StatusFlags::LIMIT_MIN | StatusFlags::FAULT
+        );
+
+        let mut legacy = [0u8; 16];
+        legacy[12..].copy_from_slice(&1.0f32.to_le_bytes());
+        let status = Status::from_bytes(legacy);
+        assert_eq!(status.flags, StatusFlags::LIMIT_MIN);
+        assert_eq!(status.flags.mode(), None);
+    }
+
+    #[test]
+    fn test_enable() {
+        assert_eq!(AIMCMessage::Enable(false).into_bytes(), [1, 0, 0, 0, 0]);
+        assert_eq!(AIMCMessage::Enable(true).into_bytes(), [1, 1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,388 @@
+// Device parameters that can be read back from an AIMC
+use crate::message::{check_crc, crc8, DeviceEndian};
+use crate::{AIMCMessage, DecodeError};
+use byteorder::ByteOrder;
+#[cfg(feature = "serde_support")]
+use serde::{Deserialize, Serialize};
+
+/// Length of a parameter reply: parameter id and value
+pub const PARAMETER_REPLY_LEN: usize = 5;
+
+/// Length of a framed parameter reply: parameter id, value, echoed sequence and CRC
+pub const FRAMED_PARAMETER_REPLY_LEN: usize = 7;
+
+/// Parameters held by the firmware. Discriminants are the ids used on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum Parameter {
+    Enabled = 1,
+    Mode = 2,
+    Kp = 3,
+    Ki = 4,
+    Kd = 5,
+    LimitPwm = 6,
+    LimitTargetMin = 7,
+    LimitTargetMax = 8,
+    EncoderPolarity = 9,
+}
+
+impl Parameter {
+    /// Every parameter, in wire id order
+    pub const ALL: [Parameter; 9] = [
+        Parameter::Enabled,
+        Parameter::Mode,
+        Parameter::Kp,
+        Parameter::Ki,
+        Parameter::Kd,
+        Parameter::LimitPwm,
+        Parameter::LimitTargetMin,
+        Parameter::LimitTargetMax,
+        Parameter::EncoderPolarity,
+    ];
+
+    /// Whether the device saves this parameter to EEPROM. Devices always power on disabled.
+    pub fn is_persisted(self) -> bool {
+        self != Parameter::Enabled
+    }
+
+    /// The parameter a message sets, if any
+    pub fn set_by(message: AIMCMessage) -> Option<Self> {
+        Some(match message {
+            AIMCMessage::Enable(_) => Parameter::Enabled,
+            AIMCMessage::ModePWM | AIMCMessage::ModePID | AIMCMessage::ModePneumatic => {
+                Parameter::Mode
+            }
+            AIMCMessage::SetKp(_) => Parameter::Kp,
+            AIMCMessage::SetKi(_) => Parameter::Ki,
+            AIMCMessage::SetKd(_) => Parameter::Kd,
+            AIMCMessage::LimitPwm(_) => Parameter::LimitPwm,
+            AIMCMessage::LimitTargetMin(_) => Parameter::LimitTargetMin,
+            AIMCMessage::LimitTargetMax(_) => Parameter::LimitTargetMax,
+            AIMCMessage::EncoderPolarity(_) => Parameter::EncoderPolarity,
+            _ => return None,
+        })
+    }
+
+    /// Look up a parameter by its wire id
+    pub fn from_id(id: u32) -> Result<Self, DecodeError> {
+        Self::ALL
+            .iter()
+            .find(|parameter| **parameter as u32 == id)
+            .copied()
+            .ok_or(DecodeError::UnknownParameter(id))
+    }
+
+    /// Interpret the raw payload of a reply for this parameter
+    pub fn decode_value(self, raw: [u8; 4]) -> Result<ParameterValue, DecodeError> {
+        let unsigned = DeviceEndian::read_u32(&raw);
+        let invalid = DecodeError::InvalidParameterValue {
+            parameter: self as u8,
+            value: unsigned,
+        };
+        Ok(match self {
+            Parameter::Kp
+            | Parameter::Ki
+            | Parameter::Kd
+            | Parameter::LimitTargetMin
+            | Parameter::LimitTargetMax => ParameterValue::Float(DeviceEndian::read_f32(&raw)),
+            Parameter::Enabled | Parameter::EncoderPolarity => match unsigned {
+                0 => ParameterValue::Bool(false),
+                1 => ParameterValue::Bool(true),
+                _ => return Err(invalid),
+            },
+            Parameter::LimitPwm if unsigned <= u32::from(u8::MAX) => {
+                ParameterValue::Pwm(unsigned as u8)
+            }
+            Parameter::Mode => {
+                ParameterValue::Mode(ControlMode::from_opcode(unsigned).ok_or(invalid)?)
+            }
+            Parameter::LimitPwm => return Err(invalid),
+        })
+    }
+}
+
+/// Control loop selected by the `Mode*` messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum ControlMode {
+    PWM,
+    PID,
+    Pneumatic,
+}
+
+impl ControlMode {
+    /// The message that selects this mode
+    pub fn message(self) -> AIMCMessage {
+        match self {
+            ControlMode::PWM => AIMCMessage::ModePWM,
+            ControlMode::PID => AIMCMessage::ModePID,
+            ControlMode::Pneumatic => AIMCMessage::ModePneumatic,
+        }
+    }
+
+    // Modes are reported using the opcode of the message that selects them
+    fn from_opcode(opcode: u32) -> Option<Self> {
+        [ControlMode::PWM, ControlMode::PID, ControlMode::Pneumatic]
+            .iter()
+            .find(|mode| u32::from(mode.message().into_bytes()[0]) == opcode)
+            .copied()
+    }
+}
+
+/// Value of a single parameter as reported by the device
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum ParameterValue {
+    Float(f32),
+    Pwm(u8),
+    Bool(bool),
+    Mode(ControlMode),
+}
+
+impl ParameterValue {
+    fn into_raw(self) -> [u8; 4] {
+        let mut raw = [0u8; 4];
+        match self {
+            ParameterValue::Float(value) => DeviceEndian::write_f32(&mut raw, value),
+            ParameterValue::Pwm(value) => DeviceEndian::write_u32(&mut raw, u32::from(value)),
+            ParameterValue::Bool(value) => DeviceEndian::write_u32(&mut raw, u32::from(value)),
+            ParameterValue::Mode(mode) => {
+                DeviceEndian::write_u32(&mut raw, u32::from(mode.message().into_bytes()[0]))
+            }
+        }
+        raw
+    }
+}
+
+/// Encode a parameter reply as the device would send it.
+pub fn parameter_reply_bytes(
+    parameter: Parameter,
+    value: ParameterValue,
+) -> [u8; PARAMETER_REPLY_LEN] {
+    let mut buffer = [0u8; PARAMETER_REPLY_LEN];
+    buffer[0] = parameter as u8;
+    buffer[1..].copy_from_slice(&value.into_raw());
+    buffer
+}
+
+/// Decode a parameter reply. Inverse of `parameter_reply_bytes`.
+pub fn parameter_reply_from_bytes(
+    bytes: [u8; PARAMETER_REPLY_LEN],
+) -> Result<(Parameter, ParameterValue), DecodeError> {
+    let parameter = Parameter::from_id(u32::from(bytes[0]))?;
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&bytes[1..]);
+    Ok((parameter, parameter.decode_value(raw)?))
+}
+
+/// Encode a framed parameter reply echoing the sequence number of the request.
+pub fn framed_parameter_reply_bytes(
+    parameter: Parameter,
+    value: ParameterValue,
+    sequence: u8,
+) -> [u8; FRAMED_PARAMETER_REPLY_LEN] {
+    let mut buffer = [0u8; FRAMED_PARAMETER_REPLY_LEN];
+    buffer[..PARAMETER_REPLY_LEN].copy_from_slice(&parameter_reply_bytes(parameter, value));
+    buffer[5] = sequence;
+    buffer[6] = crc8(&buffer[..6]);
+    buffer
+}
+
+/// Decode a framed parameter reply, returning the echoed sequence number and content.
+pub fn parameter_reply_from_framed_bytes(
+    bytes: [u8; FRAMED_PARAMETER_REPLY_LEN],
+) -> Result<(u8, Parameter, ParameterValue), DecodeError> {
+    check_crc(&bytes)?;
+    let mut reply = [0u8; PARAMETER_REPLY_LEN];
+    reply.copy_from_slice(&bytes[..PARAMETER_REPLY_LEN]);
+    let (parameter, value) = parameter_reply_from_bytes(reply)?;
+    Ok((bytes[5], parameter, value))
+}
+
+/// Snapshot of every parameter held by the firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct DeviceConfig {
+    pub enabled: bool,
+    pub mode: ControlMode,
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub limit_pwm: u8,
+    pub limit_target_min: f32,
+    pub limit_target_max: f32,
+    pub encoder_polarity: bool,
+}
+
+impl Default for DeviceConfig {
+    /// Firmware power-on defaults
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mode: ControlMode::PID,
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            limit_pwm: 255,
+            limit_target_min: 0.0,
+            limit_target_max: 0.0,
+            encoder_polarity: false,
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Update the snapshot to reflect a message having been applied by the device.
+    /// Messages that do not change a parameter are ignored, as is `LoadConfig`, whose effect
+    /// depends on what was saved.
+    pub fn apply(&mut self, message: AIMCMessage) {
+        match message {
+            AIMCMessage::Enable(enabled) => self.enabled = enabled,
+            AIMCMessage::ModePWM => self.mode = ControlMode::PWM,
+            AIMCMessage::ModePID => self.mode = ControlMode::PID,
+            AIMCMessage::ModePneumatic => self.mode = ControlMode::Pneumatic,
+            AIMCMessage::SetKp(kp) => self.kp = kp,
+            AIMCMessage::SetKi(ki) => self.ki = ki,
+            AIMCMessage::SetKd(kd) => self.kd = kd,
+            AIMCMessage::LimitPwm(limit) => self.limit_pwm = limit,
+            AIMCMessage::LimitTargetMin(min) => self.limit_target_min = min,
+            AIMCMessage::LimitTargetMax(max) => self.limit_target_max = max,
+            AIMCMessage::EncoderPolarity(polarity) => self.encoder_polarity = polarity,
+            AIMCMessage::SetTarget(_)
+            | AIMCMessage::Reset
+            | AIMCMessage::Home(_)
+            | AIMCMessage::ReadParameter(_)
+            | AIMCMessage::Heartbeat
+            | AIMCMessage::SetWatchdogTimeout(_)
+            | AIMCMessage::EnterBootloader
+            | AIMCMessage::SaveConfig
+            | AIMCMessage::LoadConfig => (),
+        }
+    }
+
+    /// Value of a single parameter
+    pub fn get(&self, parameter: Parameter) -> ParameterValue {
+        match parameter {
+            Parameter::Enabled => ParameterValue::Bool(self.enabled),
+            Parameter::Mode => ParameterValue::Mode(self.mode),
+            Parameter::Kp => ParameterValue::Float(self.kp),
+            Parameter::Ki => ParameterValue::Float(self.ki),
+            Parameter::Kd => ParameterValue::Float(self.kd),
+            Parameter::LimitPwm => ParameterValue::Pwm(self.limit_pwm),
+            Parameter::LimitTargetMin => ParameterValue::Float(self.limit_target_min),
+            Parameter::LimitTargetMax => ParameterValue::Float(self.limit_target_max),
+            Parameter::EncoderPolarity => ParameterValue::Bool(self.encoder_polarity),
+        }
+    }
+
+    /// Set a single parameter. Values of the wrong type are ignored.
+    pub fn set(&mut self, parameter: Parameter, value: ParameterValue) {
+        match (parameter, value) {
+            (Parameter::Enabled, ParameterValue::Bool(value)) => self.enabled = value,
+            (Parameter::Mode, ParameterValue::Mode(value)) => self.mode = value,
+            (Parameter::Kp, ParameterValue::Float(value)) => self.kp = value,
+            (Parameter::Ki, ParameterValue::Float(value)) => self.ki = value,
+            (Parameter::Kd, ParameterValue::Float(value)) => self.kd = value,
+            (Parameter::LimitPwm, ParameterValue::Pwm(value)) => self.limit_pwm = value,
+            (Parameter::LimitTargetMin, ParameterValue::Float(value)) => {
+                self.limit_target_min = value
+            }
+            (Parameter::LimitTargetMax, ParameterValue::Float(value)) => {
+                self.limit_target_max = value
+            }
+            (Parameter::EncoderPolarity, ParameterValue::Bool(value)) => {
+                self.encoder_polarity = value
+            }
+            _ => (),
+        }
+    }
+
+    /// Parameters whose values differ between two snapshots
+    #[cfg(any(feature = "std", test))]
+    pub fn differences(&self, other: &DeviceConfig) -> Vec<Parameter> {
+        Parameter::ALL
+            .iter()
+            .filter(|parameter| self.get(**parameter) != other.get(**parameter))
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_round_trip() {
+        let config = DeviceConfig {
+            enabled: true,
+            mode: ControlMode::Pneumatic,
+            kp: 0.5,
+            ki: -1.0,
+            kd: 2.0,
+            limit_pwm: 12,
+            limit_target_min: -3.0,
+            limit_target_max: 3.0,
+            encoder_polarity: true,
+        };
+        for parameter in Parameter::ALL.iter() {
+            let value = config.get(*parameter);
+            let bytes = parameter_reply_bytes(*parameter, value);
+            assert_eq!(
+                parameter_reply_from_bytes(bytes).unwrap(),
+                (*parameter, value)
+            );
+            let framed = framed_parameter_reply_bytes(*parameter, value, 3);
+            assert_eq!(
+                parameter_reply_from_framed_bytes(framed).unwrap(),
+                (3, *parameter, value)
+            );
+        }
+    }
+
+    #[test]
+    fn test_reply_errors() {
+        assert_eq!(
+            parameter_reply_from_bytes([0, 0, 0, 0, 0]),
+            Err(DecodeError::UnknownParameter(0))
+        );
+        assert_eq!(
+            parameter_reply_from_bytes([Parameter::Mode as u8, 3, 0, 0, 0]),
+            Err(DecodeError::InvalidParameterValue {
+                parameter: Parameter::Mode as u8,
+                value: 3
+            })
+        );
+        assert_eq!(
+            parameter_reply_from_bytes([Parameter::LimitPwm as u8, 0, 1, 0, 0]),
+            Err(DecodeError::InvalidParameterValue {
+                parameter: Parameter::LimitPwm as u8,
+                value: 256
+            })
+        );
+    }
+
+    #[test]
+    fn test_apply_and_differences() {
+        let mut config = DeviceConfig::default();
+        for message in [
+            AIMCMessage::ModePWM,
+            AIMCMessage::SetKp(1.5),
+            AIMCMessage::SetTarget(10.0),
+        ]
+        .iter()
+        {
+            config.apply(*message);
+        }
+        assert_eq!(config.mode, ControlMode::PWM);
+        assert_eq!(
+            Parameter::set_by(AIMCMessage::ModePWM),
+            Some(Parameter::Mode)
+        );
+        assert_eq!(Parameter::set_by(AIMCMessage::SaveConfig), None);
+        assert_eq!(
+            DeviceConfig::default().differences(&config),
+            vec![Parameter::Mode, Parameter::Kp]
+        );
+    }
+}
@@ -1,5 +1,11 @@
-use libaimc::{AIMCMessage, AIMC};
+use libaimc::{
+    enter_bootloader, flash_firmware, load_config, relay_autotune, save_config, step_response,
+    AIMCMessage, Bootloader, Error, FirmwareImage, I2CBus, RelayConfig, StepConfig, SystemClock,
+    Transport, TuningRule, AIMC, BOOTLOADER_ADDRESS,
+};
 use rustyline::Editor;
+use std::fs::File;
+use std::io::Write;
 mod parser;
 use parser::{Action, HELP_LINES};
 
@@ -9,6 +15,10 @@ fn main() {
     let mut args = std::env::args();
     args.next();
     let device_address = match args.next() {
+        Some(ref command) if command == "scan" => {
+            scan(&args.next().unwrap_or(DEFAULT_DEVICE_FILE.to_string()));
+            return;
+        }
         Some(addr) => match u16::from_str_radix(&addr, 16) {
             Ok(v) => v,
             Err(_) => {
@@ -17,7 +27,7 @@ fn main() {
             }
         },
         None => {
-            eprintln!("Must specify device address.");
+            eprintln!("Must specify device address, or 'scan'.");
             return;
         }
     };
@@ -26,7 +36,7 @@ fn main() {
     let mut device = match AIMC::new(&i2c_device_file, device_address) {
         Ok(d) => d,
         Err(e) => {
-            eprintln!("Could not connect to device; {:?}", e);
+            eprintln!("Could not connect to device; {}", e);
             return;
         }
     };
@@ -50,7 +60,26 @@ fn main() {
                         println!("Writing message: {:?}", msg);
                         println!("Response: {:?}", device.write_message(msg));
                     }
-                    Ok(Action::Read) => println!("Device status: {:#?}", device.status()),
+                    Ok(Action::Read) => match device.status() {
+                        Ok(status) => println!("Device status: {}", status),
+                        Err(e) => println!("Could not read status: {}", e),
+                    },
+                    Ok(Action::ReadParameter(parameter)) => {
+                        println!("{:?}: {:?}", parameter, device.read_parameter(parameter))
+                    }
+                    Ok(Action::ReadConfig) => {
+                        println!("Device config: {:#?}", device.read_config())
+                    }
+                    Ok(Action::Tune(config)) => tune(&mut device, &config),
+                    Ok(Action::Step(config, output)) => step(&mut device, &config, &output),
+                    Ok(Action::Flash(path)) => flash(&mut device, &path),
+                    Ok(Action::Save) => match save_config(&mut device, &SystemClock::new()) {
+                        Ok(config) => println!("Saved config: {:#?}", config),
+                        Err(e) => println!("Could not save config: {}", e),
+                    },
+                    Ok(Action::Load) => {
+                        println!("Loaded config: {:#?}", load_config(&mut device))
+                    }
                     Ok(Action::Help) => {
                         for line in HELP_LINES {
                             println!("{}", line);
@@ -61,3 +90,147 @@ fn main() {
         }
     }
 }
+
+/// Autotune the device, printing the gains proposed by each rule
+fn tune<T: Transport>(device: &mut AIMC<T>, config: &RelayConfig) {
+    println!(
+        "Tuning around {} with a relay of {} PWM...",
+        config.setpoint, config.relay_pwm
+    );
+    let result = match relay_autotune(device, &SystemClock::new(), config) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Tuning aborted, device disabled; {}", e);
+            return;
+        }
+    };
+    println!(
+        "Ultimate gain {:.4}, period {:?}, amplitude {:.3}",
+        result.ultimate_gain, result.ultimate_period, result.amplitude
+    );
+    for rule in TuningRule::ALL.iter() {
+        let gains = result.gains(*rule);
+        println!(
+            "{:>18}: set kp {:.4}; set ki {:.4}; set kd {:.4}",
+            format!("{:?}", rule),
+            gains.kp,
+            gains.ki,
+            gains.kd
+        );
+    }
+    println!("Device left disabled.");
+}
+
+/// Record a step response, printing its metrics and saving them with the samples
+fn step<T: Transport>(device: &mut AIMC<T>, config: &StepConfig, output: &str) {
+    println!(
+        "Stepping to {} and sampling for {:?}...",
+        config.target, config.duration
+    );
+    let response = match step_response(device, &SystemClock::new(), config) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Step response failed; {}", e);
+            return;
+        }
+    };
+    println!("{:#?}", response.metrics);
+
+    let csv_path = format!("{}.csv", output);
+    let json_path = format!("{}.json", output);
+    let written = File::create(&csv_path)
+        .and_then(|file| response.write_csv(file))
+        .and_then(|_| File::create(&json_path))
+        .and_then(|file| serde_json::to_writer_pretty(file, &response).map_err(Into::into));
+    match written {
+        Ok(()) => println!("Wrote {} and {}", csv_path, json_path),
+        Err(e) => eprintln!("Could not save step response; {}", e),
+    }
+}
+
+/// Reflash the device through its bootloader, returning to the application afterwards
+fn flash(device: &mut AIMC, path: &str) {
+    let image = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| FirmwareImage::from_intel_hex(&text).map_err(|e| e.to_string()))
+    {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Could not load {}; {}", path, e);
+            return;
+        }
+    };
+
+    let address = device.transport().address();
+    println!("Entering bootloader...");
+    match enter_bootloader(device, &SystemClock::new()) {
+        Ok(()) => (),
+        // Left in its bootloader by an earlier failed flash, so the application is not running
+        Err(Error::NoDevice { .. }) => println!(
+            "No answer at {:#04x}; trying its bootloader directly",
+            address
+        ),
+        Err(e) => {
+            eprintln!("Could not enter bootloader; {}", e);
+            return;
+        }
+    }
+    if let Err(e) = device.transport_mut().set_address(BOOTLOADER_ADDRESS) {
+        eprintln!("Could not reach bootloader; {}", e);
+        return;
+    }
+
+    let mut bootloader = Bootloader::new(device.transport_mut());
+    match bootloader.wait().and_then(|_| bootloader.version()) {
+        Ok(version) => println!("Bootloader: {}", version),
+        Err(e) => {
+            eprintln!("Bootloader did not answer; {}", e);
+            return_to_address(device, address);
+            return;
+        }
+    }
+    let flashed = flash_firmware(&mut bootloader, &image, |done, total| {
+        print!("\rWrote and verified page {}/{}", done, total);
+        let _ = std::io::stdout().flush();
+    });
+    println!();
+    match flashed {
+        Ok(info) => println!(
+            "Flashed {} bytes to chip {:02x?}; application started",
+            image.end(),
+            info.signature
+        ),
+        Err(e) => eprintln!("Flashing failed, device left in bootloader; {}", e),
+    }
+
+    return_to_address(device, address);
+}
+
+/// Talk to the application at its own address again after visiting the bootloader
+fn return_to_address(device: &mut AIMC, address: u16) {
+    if let Err(e) = device.transport_mut().set_address(address) {
+        eprintln!("Could not return to address {:#04x}; {}", address, e);
+    }
+}
+
+/// Print every AIMC found on the bus
+fn scan(i2c_device_file: &str) {
+    let mut bus = match I2CBus::open(i2c_device_file) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Could not open bus; {}", e);
+            return;
+        }
+    };
+
+    eprintln!("Scanning I2C device file: {}", i2c_device_file);
+    match libaimc::scan(&mut bus) {
+        Err(e) => eprintln!("Scan failed; {}", e),
+        Ok(discovered) if discovered.is_empty() => println!("No AIMCs found."),
+        Ok(discovered) => {
+            for device in discovered {
+                println!("{:#04x}: {:?}", device.address, device.status);
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use libaimc::AIMCMessage;
+use libaimc::{AIMCMessage, Parameter, RelayConfig, StepConfig};
 use std::fmt;
+use std::time::Duration;
 
 pub const HELP_LINES: &[&str] = &[
     "AIMC Jogger help:",
@@ -16,9 +17,22 @@ pub const HELP_LINES: &[&str] = &[
     "\tset mode_pneu <int> // Change to Pneumatic control mode",
     "\tset ltma <float>    // Set target limit max",
     "\tset ltmi <float>    // Set target limit min",
+    "\tset watchdog <int>  // Disable after this many ms without a message, or 0 for never",
+    "\tset heartbeat       // Send a heartbeat",
     "\tget                 // Return evaluation of internal variables",
+    "\tget <param>         // Read back a parameter (enabled, mode, kp, ki, kd, limit, ltmi, ltma, polarity)",
+    "\tget config          // Read back every parameter",
+    "\ttune <setpoint> [relay pwm] [max excursion] // Relay-feedback autotune around setpoint",
+    "\tstep <target> [seconds] [output] // Record a step response to <output>.csv and .json",
+    "\tflash <file.hex>    // Reflash the firmware through the I2C bootloader",
+    "\tsave                // Save the current parameters to EEPROM, to be used from power-on",
+    "\tload                // Replace the current parameters with those saved in EEPROM",
 ];
 
+/// File name stem for step responses when none is given
+const DEFAULT_STEP_OUTPUT: &str = "step";
+
+#[derive(Debug)]
 pub enum ActionParseError<'a> {
     Unrecognized(&'a str),
     MissingArg(&'static str),
@@ -40,6 +54,13 @@ pub enum Action {
     Write(AIMCMessage),
     Help,
     Read,
+    ReadParameter(Parameter),
+    ReadConfig,
+    Tune(RelayConfig),
+    Step(StepConfig, String),
+    Flash(String),
+    Save,
+    Load,
 }
 
 fn parse_arg<'a, T: std::str::FromStr>(
@@ -72,6 +93,70 @@ fn aimcmessage_from_str<'a>(
         "mode_pwm" | "mpw" => Ok(AIMCMessage::ModePWM),
         "mode_pid" | "mpi" => Ok(AIMCMessage::ModePID),
         "mode_pneu" | "mpn" => Ok(AIMCMessage::ModePneumatic),
+        "watchdog" | "wd" => Ok(AIMCMessage::SetWatchdogTimeout(parse_arg(args, "timeout")?)),
+        "heartbeat" | "hb" => Ok(AIMCMessage::Heartbeat),
+        other => Err(ActionParseError::Unrecognized(other)),
+    }
+}
+
+/// Parse an optional trailing argument, falling back to the default when absent
+fn parse_optional_arg<'a, T: std::str::FromStr>(
+    args: &mut impl Iterator<Item = &'a str>,
+    default: T,
+) -> Result<T, ActionParseError<'a>> {
+    match args.next() {
+        Some(text) => text.parse::<T>().map_err(|_| ActionParseError::At(text)),
+        None => Ok(default),
+    }
+}
+
+fn relayconfig_from_str<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+) -> Result<RelayConfig, ActionParseError<'a>> {
+    let defaults = RelayConfig::default();
+    Ok(RelayConfig {
+        setpoint: parse_arg(args, "setpoint")?,
+        relay_pwm: parse_optional_arg(args, defaults.relay_pwm)?,
+        max_excursion: parse_optional_arg(args, defaults.max_excursion)?,
+        ..defaults
+    })
+}
+
+fn stepconfig_from_str<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+) -> Result<(StepConfig, String), ActionParseError<'a>> {
+    let defaults = StepConfig::default();
+    let target = parse_arg(args, "target")?;
+    let duration = match args.next() {
+        // Infinite or negative durations would panic, and mean nothing anyway
+        Some(text) => text
+            .parse::<f32>()
+            .ok()
+            .filter(|seconds| seconds.is_finite())
+            .map(|seconds| Duration::from_secs_f32(seconds.max(0.0)))
+            .ok_or(ActionParseError::At(text))?,
+        None => defaults.duration,
+    };
+    let config = StepConfig {
+        target,
+        duration,
+        ..defaults
+    };
+    let output = args.next().unwrap_or(DEFAULT_STEP_OUTPUT).to_string();
+    Ok((config, output))
+}
+
+fn parameter_from_str(text: &str) -> Result<Parameter, ActionParseError<'_>> {
+    match text {
+        "enable" | "enabled" | "e" => Ok(Parameter::Enabled),
+        "mode" | "m" => Ok(Parameter::Mode),
+        "kp" | "p" => Ok(Parameter::Kp),
+        "ki" | "i" => Ok(Parameter::Ki),
+        "kd" | "d" => Ok(Parameter::Kd),
+        "limit" | "lim" => Ok(Parameter::LimitPwm),
+        "limittargetmin" | "ltmi" => Ok(Parameter::LimitTargetMin),
+        "limittargetmax" | "ltma" => Ok(Parameter::LimitTargetMax),
+        "polarity" | "pl" => Ok(Parameter::EncoderPolarity),
         other => Err(ActionParseError::Unrecognized(other)),
     }
 }
@@ -84,8 +169,63 @@ impl Action {
         match args.next().unwrap_or("g") {
             "set" | "write" | "s" => Ok(Action::Write(aimcmessage_from_str(args)?)),
             "help" => Ok(Action::Help),
-            "get" | "read" | "g" => Ok(Action::Read),
+            "tune" => Ok(Action::Tune(relayconfig_from_str(args)?)),
+            "flash" => Ok(Action::Flash(parse_arg(args, "file")?)),
+            "save" => Ok(Action::Save),
+            "load" => Ok(Action::Load),
+            "step" => {
+                let (config, output) = stepconfig_from_str(args)?;
+                Ok(Action::Step(config, output))
+            }
+            "get" | "read" | "g" => match args.next() {
+                None => Ok(Action::Read),
+                Some("config" | "c") => Ok(Action::ReadConfig),
+                Some(parameter) => Ok(Action::ReadParameter(parameter_from_str(parameter)?)),
+            },
             other => Err(ActionParseError::Unrecognized(other)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libaimc::{read_capture, ReplayTransport, AIMC};
+
+    /// A jog session captured on the robot
+    const SESSION: &str = "\
+# aimc capture v1
+0.000000 /dev/i2c-1 0x10 W 0a00000000
+0.812000 /dev/i2c-1 0x10 W 020000c03f
+1.375000 /dev/i2c-1 0x10 W 0101000000
+2.004000 /dev/i2c-1 0x10 W 1000000000
+";
+
+    #[test]
+    fn test_replay_session() {
+        let records = read_capture(SESSION.as_bytes()).unwrap();
+        let mut device = AIMC::from_transport(ReplayTransport::new(records));
+        for line in &["set home 0", "s t 1.5", "set enable true", "set hb"] {
+            match Action::from_commandline(&mut line.split_whitespace()) {
+                Ok(Action::Write(message)) => device.write_message(message).unwrap(),
+                other => panic!("Expected '{}' to write, got {:?}", line, other),
+            }
+        }
+        assert_eq!(device.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn test_step_duration() {
+        match Action::from_commandline(&mut "step 1 -2".split_whitespace()) {
+            Ok(Action::Step(config, _)) => assert_eq!(config.duration, Duration::from_secs(0)),
+            other => panic!("Expected a step, got {:?}", other),
+        }
+        for seconds in &["inf", "-inf", "NaN"] {
+            let line = format!("step 1 {}", seconds);
+            match Action::from_commandline(&mut line.split_whitespace()) {
+                Err(ActionParseError::At(text)) => assert_eq!(text, *seconds),
+                other => panic!("Expected '{}' to be rejected, got {:?}", line, other),
+            }
+        }
+    }
+}
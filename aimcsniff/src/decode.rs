@@ -0,0 +1,243 @@
+use libaimc::{
+    parameter_reply_from_bytes, parameter_reply_from_framed_bytes, parse_hex, AIMCMessage,
+    CaptureParseError, CaptureRecord, DecodeError, Direction, Parameter, ParameterValue, Status,
+    FRAMED_MESSAGE_LEN, FRAMED_PARAMETER_REPLY_LEN, FRAMED_STATUS_LEN, PARAMETER_REPLY_LEN,
+};
+use std::convert::TryInto;
+use std::fmt;
+use std::time::Duration;
+
+/// Length of a bare message or status
+const MESSAGE_LEN: usize = 5;
+const STATUS_LEN: usize = 16;
+
+/// A transaction, as worked out from its direction and length
+#[derive(Debug)]
+pub enum Decoded {
+    Message(AIMCMessage),
+    FramedMessage {
+        sequence: u8,
+        message: AIMCMessage,
+    },
+    Status(Status),
+    FramedStatus {
+        sequence: u8,
+        status: Status,
+    },
+    ParameterReply(Parameter, ParameterValue),
+    FramedParameterReply {
+        sequence: u8,
+        parameter: Parameter,
+        value: ParameterValue,
+    },
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decoded::Message(message) => write!(f, "{:?}", message),
+            Decoded::FramedMessage { sequence, message } => {
+                write!(f, "{:?} (seq {})", message, sequence)
+            }
+            Decoded::Status(status) => write!(f, "{}", status),
+            Decoded::FramedStatus { sequence, status } => {
+                write!(f, "{} (seq {})", status, sequence)
+            }
+            Decoded::ParameterReply(parameter, value) => write!(f, "{:?} = {:?}", parameter, value),
+            Decoded::FramedParameterReply {
+                sequence,
+                parameter,
+                value,
+            } => write!(f, "{:?} = {:?} (seq {})", parameter, value, sequence),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SniffError {
+    /// No message or reply has this length
+    UnknownLength(Direction, usize),
+    Decode(DecodeError),
+}
+
+impl fmt::Display for SniffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SniffError::UnknownLength(direction, len) => {
+                write!(f, "No {:?} is {} bytes long", direction, len)
+            }
+            SniffError::Decode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<DecodeError> for SniffError {
+    fn from(e: DecodeError) -> Self {
+        SniffError::Decode(e)
+    }
+}
+
+/// Decode a transaction. Every message and reply has a distinct length in its direction, so
+/// bare and framed traffic can be told apart without being told which to expect.
+pub fn decode(direction: Direction, data: &[u8]) -> Result<Decoded, SniffError> {
+    let unknown = || SniffError::UnknownLength(direction, data.len());
+    Ok(match (direction, data.len()) {
+        (Direction::Write, MESSAGE_LEN) => {
+            Decoded::Message(AIMCMessage::from_bytes(data.try_into().unwrap())?)
+        }
+        (Direction::Write, FRAMED_MESSAGE_LEN) => {
+            let (sequence, message) = AIMCMessage::from_framed_bytes(data.try_into().unwrap())?;
+            Decoded::FramedMessage { sequence, message }
+        }
+        (Direction::Read, STATUS_LEN) => {
+            Decoded::Status(Status::from_bytes(data.try_into().unwrap()))
+        }
+        (Direction::Read, FRAMED_STATUS_LEN) => {
+            let (sequence, status) = Status::from_framed_bytes(data.try_into().unwrap())?;
+            Decoded::FramedStatus { sequence, status }
+        }
+        (Direction::Read, PARAMETER_REPLY_LEN) => {
+            let (parameter, value) = parameter_reply_from_bytes(data.try_into().unwrap())?;
+            Decoded::ParameterReply(parameter, value)
+        }
+        (Direction::Read, FRAMED_PARAMETER_REPLY_LEN) => {
+            let (sequence, parameter, value) =
+                parameter_reply_from_framed_bytes(data.try_into().unwrap())?;
+            Decoded::FramedParameterReply {
+                sequence,
+                parameter,
+                value,
+            }
+        }
+        _ => return Err(unknown()),
+    })
+}
+
+/// Opcode of a written message, whether or not it decodes
+pub fn opcode(direction: Direction, data: &[u8]) -> Option<u8> {
+    match (direction, data.len()) {
+        (Direction::Write, MESSAGE_LEN) => Some(data[0]),
+        (Direction::Write, FRAMED_MESSAGE_LEN) => Some(data[1]),
+        _ => None,
+    }
+}
+
+/// Layout of the lines being sniffed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The libaimc capture format
+    Capture,
+    /// `<address> <W|R> <bytes>`, with the bytes in hex and optionally space-separated
+    Raw,
+}
+
+impl Format {
+    /// Guess the format from the first transaction in the input
+    pub fn detect(line: &str) -> Self {
+        if line.parse::<CaptureRecord>().is_ok() {
+            Format::Capture
+        } else {
+            Format::Raw
+        }
+    }
+
+    pub fn parse(self, line: &str) -> Result<CaptureRecord, CaptureParseError> {
+        match self {
+            Format::Capture => line.parse(),
+            Format::Raw => parse_raw(line),
+        }
+    }
+}
+
+/// Parse a raw hex line into a record without a time or bus
+fn parse_raw(line: &str) -> Result<CaptureRecord, CaptureParseError> {
+    let mut fields = line.split_whitespace();
+    let mut field = |name: &'static str| fields.next().ok_or(CaptureParseError::Missing(name));
+    let address = field("address")?;
+    let address = u16::from_str_radix(address.trim_start_matches("0x"), 16)
+        .map_err(|_| CaptureParseError::Invalid("address", address.to_string()))?;
+    let direction = match field("direction")? {
+        "W" | "w" => Direction::Write,
+        "R" | "r" => Direction::Read,
+        other => return Err(CaptureParseError::Invalid("direction", other.to_string())),
+    };
+    let data: String = fields.collect();
+    let data = parse_hex(&data).ok_or(CaptureParseError::Invalid("data", data))?;
+    Ok(CaptureRecord {
+        time: Duration::default(),
+        bus: String::new(),
+        address,
+        direction,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libaimc::{framed_parameter_reply_bytes, ControlMode, StatusFlags};
+
+    #[test]
+    fn test_decode() {
+        let message = AIMCMessage::SetTarget(1.5);
+        assert!(matches!(
+            decode(Direction::Write, &message.into_bytes()),
+            Ok(Decoded::Message(AIMCMessage::SetTarget(t))) if t == 1.5
+        ));
+        assert!(matches!(
+            decode(Direction::Write, &message.into_framed_bytes(7)),
+            Ok(Decoded::FramedMessage { sequence: 7, .. })
+        ));
+        assert_eq!(
+            opcode(Direction::Write, &message.into_framed_bytes(7)),
+            Some(2)
+        );
+
+        let status = Status {
+            encoder: 1.0,
+            target: 2.0,
+            pid_out: 3.0,
+            flags: StatusFlags::ENABLED.with_mode(ControlMode::PID),
+        };
+        assert_eq!(
+            decode(Direction::Read, &status.into_framed_bytes(3))
+                .unwrap()
+                .to_string(),
+            "encoder 1, target 2, pid_out 3, flags enabled|mode=PID (seq 3)"
+        );
+        let reply = framed_parameter_reply_bytes(Parameter::Kp, ParameterValue::Float(0.5), 1);
+        assert!(matches!(
+            decode(Direction::Read, &reply),
+            Ok(Decoded::FramedParameterReply {
+                parameter: Parameter::Kp,
+                ..
+            })
+        ));
+
+        assert_eq!(
+            decode(Direction::Write, &[0, 0, 0, 0, 0]).unwrap_err(),
+            SniffError::Decode(DecodeError::UnknownOpcode(0))
+        );
+        assert_eq!(
+            decode(Direction::Read, &[0; 3]).unwrap_err(),
+            SniffError::UnknownLength(Direction::Read, 3)
+        );
+    }
+
+    #[test]
+    fn test_formats() {
+        let capture = "1.250000 /dev/i2c-1 0x10 W 020000c03f";
+        let raw = "0x10 W 02 00 00 c0 3f";
+        assert_eq!(Format::detect(capture), Format::Capture);
+        assert_eq!(Format::detect(raw), Format::Raw);
+
+        let from_capture = Format::Capture.parse(capture).unwrap();
+        let from_raw = Format::Raw.parse(raw).unwrap();
+        assert_eq!(from_raw.address, from_capture.address);
+        assert_eq!(from_raw.data, from_capture.data);
+        assert_eq!(
+            Format::Raw.parse("10 R 0").unwrap_err(),
+            CaptureParseError::Invalid("data", "0".to_string())
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use libaimc::{CaptureRecord, Direction};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+mod decode;
+use decode::{decode, opcode, Format};
+
+const USAGE: &str = "\
+Usage: aimcsniff [--address <hex>]... [--opcode <int>]... [file]
+Decode AIMC traffic from a libaimc capture, or from raw lines of '<address> <W|R> <hex bytes>'.
+Reads standard input if no file is given. Filters of the same kind may be repeated; filtering by
+opcode shows only the messages written with those opcodes.";
+
+#[derive(Default)]
+struct Filter {
+    addresses: Vec<u16>,
+    opcodes: Vec<u8>,
+}
+
+impl Filter {
+    fn matches(&self, record: &CaptureRecord) -> bool {
+        let address = self.addresses.is_empty() || self.addresses.contains(&record.address);
+        let opcode = self.opcodes.is_empty()
+            || opcode(record.direction, &record.data).is_some_and(|op| self.opcodes.contains(&op));
+        address && opcode
+    }
+}
+
+fn main() {
+    let mut filter = Filter::default();
+    let mut path = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--address" | "-a" => match args
+                .next()
+                .and_then(|a| u16::from_str_radix(a.trim_start_matches("0x"), 16).ok())
+            {
+                Some(address) => filter.addresses.push(address),
+                None => return eprintln!("Expected a hex address after {}", arg),
+            },
+            "--opcode" | "-o" => match args.next().and_then(|o| o.parse().ok()) {
+                Some(opcode) => filter.opcodes.push(opcode),
+                None => return eprintln!("Expected an opcode after {}", arg),
+            },
+            "--help" | "-h" => return eprintln!("{}", USAGE),
+            _ if path.is_none() => path = Some(arg),
+            _ => return eprintln!("{}", USAGE),
+        }
+    }
+
+    let input: Box<dyn BufRead> = match path.as_deref() {
+        None | Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => return eprintln!("Could not open {}: {}", path, e),
+        },
+    };
+
+    if let Err(e) = sniff(input, &filter) {
+        eprintln!("Could not read input: {}", e);
+    }
+}
+
+/// Print every transaction in the input that passes the filter
+fn sniff<R: BufRead>(input: R, filter: &Filter) -> io::Result<()> {
+    let mut format = None;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let format = *format.get_or_insert_with(|| Format::detect(line));
+        let record = match format.parse(line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("Line {}: {}", index + 1, e);
+                continue;
+            }
+        };
+        if !filter.matches(&record) {
+            continue;
+        }
+
+        if format == Format::Capture {
+            print!(
+                "{:>4}.{:06} {} ",
+                record.time.as_secs(),
+                record.time.subsec_micros(),
+                record.bus
+            );
+        }
+        let arrow = match record.direction {
+            Direction::Write => "->",
+            Direction::Read => "<-",
+        };
+        match decode(record.direction, &record.data) {
+            Ok(decoded) => println!("{:#04x} {} {}", record.address, arrow, decoded),
+            Err(e) => println!(
+                "{:#04x} {} {:02x?} ({})",
+                record.address, arrow, record.data, e
+            ),
+        }
+    }
+    Ok(())
+}
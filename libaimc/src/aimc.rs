@@ -1,30 +1,290 @@
 // Arduino-based Intelligent Motor Controller protocol
-use crate::{Status, AIMCMessage};
-pub use i2cdev::linux::LinuxI2CError;
-use i2cdev::{core::I2CDevice, linux::LinuxI2CDevice};
+use crate::{
+    parameter_reply_from_bytes, parameter_reply_from_framed_bytes, AIMCMessage, DecodeError,
+    DeviceConfig, Error, I2CTransport, Parameter, ParameterValue, ProtocolVersion, SerialTransport,
+    Status, Transport, FRAMED_PARAMETER_REPLY_LEN, FRAMED_STATUS_LEN, PARAMETER_REPLY_LEN,
+};
 use std::path::Path;
 
-pub struct AIMC {
-    i2c_device: LinuxI2CDevice,
+pub struct AIMC<T = I2CTransport> {
+    transport: T,
+    protocol: ProtocolVersion,
+    sequence: u8,
 }
 
-impl AIMC {
+impl AIMC<I2CTransport> {
     /// Create a new AIMC from the specified I2C device file and address.
-    pub fn new<P: AsRef<Path>>(i2c_device_file: P, address: u16) -> Result<Self, LinuxI2CError> {
-        Ok(Self {
-            i2c_device: LinuxI2CDevice::new(i2c_device_file, address)?,
-        })
+    pub fn new<P: AsRef<Path>>(i2c_device_file: P, address: u16) -> Result<Self, Error> {
+        Ok(Self::from_transport(I2CTransport::open(
+            i2c_device_file,
+            address,
+        )?))
+    }
+}
+
+impl AIMC<SerialTransport> {
+    /// Create a new AIMC from the specified serial port and baud rate.
+    pub fn serial<P: AsRef<Path>>(serial_port: P, baud: u32) -> Result<Self, Error> {
+        Ok(Self::from_transport(SerialTransport::open(
+            serial_port,
+            baud,
+        )?))
+    }
+}
+
+impl<T: Transport> AIMC<T> {
+    /// Create a new AIMC communicating over an existing transport.
+    pub fn from_transport(transport: T) -> Self {
+        Self {
+            transport,
+            protocol: ProtocolVersion::Bare,
+            sequence: 0,
+        }
+    }
+
+    /// Use the specified wire format for all further communication
+    pub fn with_protocol(mut self, protocol: ProtocolVersion) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Wire format in use
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.protocol
+    }
+
+    /// Borrow the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Mutably borrow the underlying transport
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Consume the AIMC, returning the underlying transport
+    pub fn into_transport(self) -> T {
+        self.transport
     }
 
     /// Write a message to the device
-    pub fn write_message(&mut self, message: AIMCMessage) -> Result<(), LinuxI2CError> {
-        self.i2c_device.write(&message.into_bytes())
+    pub fn write_message(&mut self, message: AIMCMessage) -> Result<(), Error> {
+        match self.protocol {
+            ProtocolVersion::Bare => self.transport.write(&message.into_bytes()),
+            ProtocolVersion::Framed => {
+                self.sequence = self.sequence.wrapping_add(1);
+                self.transport
+                    .write(&message.into_framed_bytes(self.sequence))
+            }
+        }
     }
 
     /// Read the encoder
-    pub fn status(&mut self) -> Result<Status, LinuxI2CError> {
-        let mut buffer = [0u8; 16];
-        self.i2c_device.read(&mut buffer)?;
-        Ok(Status::from_bytes(buffer))
+    pub fn status(&mut self) -> Result<Status, Error> {
+        match self.protocol {
+            ProtocolVersion::Bare => {
+                let mut buffer = [0u8; 16];
+                self.transport.read(&mut buffer)?;
+                Ok(Status::from_bytes(buffer))
+            }
+            ProtocolVersion::Framed => {
+                let mut buffer = [0u8; FRAMED_STATUS_LEN];
+                self.transport.read(&mut buffer)?;
+                let (sequence, status) = Status::from_framed_bytes(buffer)?;
+                if sequence != self.sequence {
+                    return Err(Error::StaleStatus {
+                        expected: self.sequence,
+                        actual: sequence,
+                    });
+                }
+                Ok(status)
+            }
+        }
+    }
+
+    /// Request a single parameter from the device and read back its value
+    pub fn read_parameter(&mut self, parameter: Parameter) -> Result<ParameterValue, Error> {
+        self.write_message(AIMCMessage::ReadParameter(parameter))?;
+        let (actual, value) = match self.protocol {
+            ProtocolVersion::Bare => {
+                let mut buffer = [0u8; PARAMETER_REPLY_LEN];
+                self.transport.read(&mut buffer)?;
+                parameter_reply_from_bytes(buffer)?
+            }
+            ProtocolVersion::Framed => {
+                let mut buffer = [0u8; FRAMED_PARAMETER_REPLY_LEN];
+                self.transport.read(&mut buffer)?;
+                let (sequence, parameter, value) = parameter_reply_from_framed_bytes(buffer)?;
+                if sequence != self.sequence {
+                    return Err(Error::StaleStatus {
+                        expected: self.sequence,
+                        actual: sequence,
+                    });
+                }
+                (parameter, value)
+            }
+        };
+        if actual != parameter {
+            return Err(DecodeError::UnexpectedParameter {
+                expected: parameter as u8,
+                actual: actual as u8,
+            }
+            .into());
+        }
+        Ok(value)
+    }
+
+    /// Read back every parameter from the device
+    pub fn read_config(&mut self) -> Result<DeviceConfig, Error> {
+        let mut config = DeviceConfig::default();
+        for parameter in Parameter::ALL.iter() {
+            config.set(*parameter, self.read_parameter(*parameter)?);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parameter_reply_bytes, ControlMode, MemoryTransport, StatusFlags};
+
+    fn written(message: AIMCMessage) -> Vec<u8> {
+        let mut aimc = AIMC::from_transport(MemoryTransport::new());
+        aimc.write_message(message).unwrap();
+        let mut writes = aimc.into_transport().take_writes();
+        assert_eq!(writes.len(), 1);
+        writes.remove(0)
+    }
+
+    #[test]
+    fn test_message_bytes() {
+        let cases = [
+            (AIMCMessage::Enable(true), [1, 1, 0, 0, 0]),
+            (AIMCMessage::SetTarget(1.5), [2, 0x00, 0x00, 0xC0, 0x3F]),
+            (AIMCMessage::Reset, [3, 0, 0, 0, 0]),
+            (AIMCMessage::ModePWM, [4, 0, 0, 0, 0]),
+            (AIMCMessage::ModePID, [5, 0, 0, 0, 0]),
+            (AIMCMessage::ModePneumatic, [6, 0, 0, 0, 0]),
+            (AIMCMessage::SetKp(-2.0), [7, 0x00, 0x00, 0x00, 0xC0]),
+            (AIMCMessage::SetKi(0.5), [8, 0x00, 0x00, 0x00, 0x3F]),
+            (AIMCMessage::SetKd(1.0), [9, 0x00, 0x00, 0x80, 0x3F]),
+            (AIMCMessage::Home(-2), [10, 0xFE, 0xFF, 0xFF, 0xFF]),
+            (AIMCMessage::LimitPwm(200), [11, 200, 0, 0, 0]),
+            (
+                AIMCMessage::LimitTargetMin(-1.0),
+                [12, 0x00, 0x00, 0x80, 0xBF],
+            ),
+            (
+                AIMCMessage::LimitTargetMax(2.0),
+                [13, 0x00, 0x00, 0x00, 0x40],
+            ),
+            (AIMCMessage::EncoderPolarity(true), [14, 1, 0, 0, 0]),
+            (AIMCMessage::ReadParameter(Parameter::Kd), [15, 5, 0, 0, 0]),
+            (AIMCMessage::Heartbeat, [16, 0, 0, 0, 0]),
+            (AIMCMessage::SetWatchdogTimeout(500), [17, 0xF4, 0x01, 0, 0]),
+            (AIMCMessage::EnterBootloader, [18, 0, 0, 0, 0]),
+            (AIMCMessage::SaveConfig, [19, 0, 0, 0, 0]),
+            (AIMCMessage::LoadConfig, [20, 0, 0, 0, 0]),
+        ];
+        for (message, bytes) in cases.iter() {
+            assert_eq!(written(*message), bytes.to_vec(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn test_status() {
+        let status = Status {
+            encoder: 12.5,
+            target: 10.0,
+            pid_out: -3.25,
+            flags: StatusFlags::LIMIT_MIN | StatusFlags::ENABLED,
+        };
+        let mut transport = MemoryTransport::new();
+        transport.queue_read(&status.into_bytes());
+
+        let mut aimc = AIMC::from_transport(transport);
+        let read = aimc.status().unwrap();
+        assert_eq!(read.encoder, 12.5);
+        assert_eq!(read.target, 10.0);
+        assert_eq!(read.pid_out, -3.25);
+        assert_eq!(read.flags, StatusFlags::LIMIT_MIN | StatusFlags::ENABLED);
+        assert!(aimc.transport().writes().is_empty());
+        assert!(aimc.status().is_err());
+    }
+
+    #[test]
+    fn test_framed() {
+        let status = Status {
+            encoder: 1.0,
+            target: 2.0,
+            pid_out: 3.0,
+            flags: StatusFlags::empty(),
+        };
+        let mut transport = MemoryTransport::new();
+        transport.queue_read(&status.into_framed_bytes(2));
+        transport.queue_read(&status.into_framed_bytes(1));
+        let mut corrupted = status.into_framed_bytes(2);
+        corrupted[0] ^= 0x01;
+        transport.queue_read(&corrupted);
+
+        let mut aimc = AIMC::from_transport(transport).with_protocol(ProtocolVersion::Framed);
+        aimc.write_message(AIMCMessage::Reset).unwrap();
+        aimc.write_message(AIMCMessage::Enable(true)).unwrap();
+        assert_eq!(
+            aimc.transport().writes(),
+            &[
+                AIMCMessage::Reset.into_framed_bytes(1).to_vec(),
+                AIMCMessage::Enable(true).into_framed_bytes(2).to_vec(),
+            ]
+        );
+
+        assert_eq!(aimc.status().unwrap().encoder, 1.0);
+        assert!(matches!(
+            aimc.status(),
+            Err(Error::StaleStatus {
+                expected: 2,
+                actual: 1
+            })
+        ));
+        assert!(matches!(
+            aimc.status(),
+            Err(Error::Decode(DecodeError::Checksum { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_read_parameter() {
+        let mut transport = MemoryTransport::new();
+        transport.queue_read(&parameter_reply_bytes(
+            Parameter::Mode,
+            ParameterValue::Mode(ControlMode::PWM),
+        ));
+        transport.queue_read(&parameter_reply_bytes(
+            Parameter::Ki,
+            ParameterValue::Float(1.0),
+        ));
+
+        let mut aimc = AIMC::from_transport(transport);
+        assert_eq!(
+            aimc.read_parameter(Parameter::Mode).unwrap(),
+            ParameterValue::Mode(ControlMode::PWM)
+        );
+        assert!(matches!(
+            aimc.read_parameter(Parameter::Kp),
+            Err(Error::Decode(DecodeError::UnexpectedParameter { .. }))
+        ));
+        assert_eq!(
+            aimc.transport().writes(),
+            &[
+                AIMCMessage::ReadParameter(Parameter::Mode)
+                    .into_bytes()
+                    .to_vec(),
+                AIMCMessage::ReadParameter(Parameter::Kp)
+                    .into_bytes()
+                    .to_vec(),
+            ]
+        );
     }
 }
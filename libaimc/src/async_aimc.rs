@@ -0,0 +1,191 @@
+// Non-blocking access to an AIMC for tokio-based applications
+use crate::{AIMCMessage, DeviceConfig, Error, Parameter, ParameterValue, Status, Transport, AIMC};
+use std::sync::mpsc;
+use std::time::Duration;
+use std::{error, fmt, thread};
+use tokio::sync::oneshot;
+
+type Job<T> = Box<dyn FnOnce(&mut AIMC<T>) + Send>;
+
+/// Handle to an AIMC owned by a dedicated bus thread.
+///
+/// Calls are queued and run on the bus thread in order. Dropping a call's future before the bus
+/// thread gets to it cancels the call; one already under way runs to completion, and its result
+/// is discarded. Handles are cheap to clone, and every clone shares the same bus thread, so a
+/// single call can be given its own timeout with `aimc.clone().with_timeout(..)`.
+pub struct AsyncAIMC<T> {
+    jobs: mpsc::Sender<Job<T>>,
+    timeout: Option<Duration>,
+}
+
+impl<T> Clone for AsyncAIMC<T> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<T: Transport + Send + 'static> AsyncAIMC<T> {
+    /// Move the AIMC onto a new bus thread. The thread exits once every handle is dropped.
+    pub fn spawn(mut aimc: AIMC<T>) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job<T>>();
+        thread::Builder::new()
+            .name("aimc-bus".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    job(&mut aimc);
+                }
+            })
+            .expect("Failed to spawn AIMC bus thread");
+        Self {
+            jobs,
+            timeout: None,
+        }
+    }
+
+    /// Give up on each call after this long, returning `Error::Timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run an arbitrary operation on the bus thread
+    pub async fn call<R, F>(&self, operation: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AIMC<T>) -> Result<R, Error> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job<T> = Box::new(move |aimc| {
+            // Nobody is waiting any more: the call was cancelled or timed out while queued
+            if reply.is_closed() {
+                return;
+            }
+            let _ = reply.send(operation(aimc));
+        });
+        self.jobs.send(job).map_err(|_| stopped())?;
+
+        let result = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, result)
+                .await
+                .map_err(|_| Error::Timeout)?,
+            None => result.await,
+        };
+        result.map_err(|_| stopped())?
+    }
+
+    /// Write a message to the device
+    pub async fn write_message(&self, message: AIMCMessage) -> Result<(), Error> {
+        self.call(move |aimc| aimc.write_message(message)).await
+    }
+
+    /// Read the encoder
+    pub async fn status(&self) -> Result<Status, Error> {
+        self.call(|aimc| aimc.status()).await
+    }
+
+    /// Request a single parameter from the device and read back its value
+    pub async fn read_parameter(&self, parameter: Parameter) -> Result<ParameterValue, Error> {
+        self.call(move |aimc| aimc.read_parameter(parameter)).await
+    }
+
+    /// Read back every parameter from the device
+    pub async fn read_config(&self) -> Result<DeviceConfig, Error> {
+        self.call(|aimc| aimc.read_config()).await
+    }
+}
+
+fn stopped() -> Error {
+    Error::Transport(Box::new(BusThreadStopped))
+}
+
+/// The bus thread is gone, most likely because an operation on it panicked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusThreadStopped;
+
+impl fmt::Display for BusThreadStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AIMC bus thread stopped")
+    }
+}
+
+impl error::Error for BusThreadStopped {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, PlantModel, SimulatedAIMC};
+    use std::sync::{Arc, Mutex};
+
+    /// Records writes; each read announces itself, then blocks until the gate is opened once
+    struct GatedTransport {
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+        reading: mpsc::Sender<()>,
+        gate: mpsc::Receiver<()>,
+    }
+
+    impl Transport for GatedTransport {
+        fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.writes.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+            self.reading.send(()).unwrap();
+            self.gate.recv().unwrap();
+            buffer.iter_mut().for_each(|b| *b = 0);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulated() {
+        let simulated = SimulatedAIMC::with_clock(PlantModel::default(), ManualClock::new());
+        let aimc = AsyncAIMC::spawn(AIMC::from_transport(simulated));
+        aimc.write_message(AIMCMessage::SetTarget(4.0))
+            .await
+            .unwrap();
+        assert_eq!(aimc.status().await.unwrap().target, 4.0);
+        assert_eq!(
+            aimc.read_parameter(Parameter::LimitPwm).await.unwrap(),
+            ParameterValue::Pwm(255)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_and_cancellation() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let (reading, entered) = mpsc::channel();
+        let (open, gate) = mpsc::channel();
+        let aimc = AsyncAIMC::spawn(AIMC::from_transport(GatedTransport {
+            writes: writes.clone(),
+            reading,
+            gate,
+        }));
+        let hasty = aimc.clone().with_timeout(Duration::from_millis(10));
+
+        // Stuck on the bus thread until the gate opens
+        let stuck = aimc.status();
+        tokio::pin!(stuck);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut stuck)
+            .await
+            .is_err());
+        entered.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        // Queued behind it, then abandoned
+        assert!(matches!(
+            hasty.write_message(AIMCMessage::Reset).await,
+            Err(Error::Timeout)
+        ));
+
+        open.send(()).unwrap();
+        stuck.await.unwrap();
+        aimc.write_message(AIMCMessage::Enable(true)).await.unwrap();
+        assert_eq!(
+            *writes.lock().unwrap(),
+            vec![AIMCMessage::Enable(true).into_bytes().to_vec()]
+        );
+    }
+}
@@ -0,0 +1,431 @@
+// Relay-feedback (Åström–Hägglund) PID autotuning
+use crate::{AIMCMessage, Clock, ControlMode, Error, Parameter, ParameterValue, Transport, AIMC};
+use std::f32::consts::PI;
+use std::time::Duration;
+use std::{error, fmt};
+
+/// Settings for a relay-feedback experiment.
+///
+/// The device is switched to PWM mode and driven at `+relay_pwm` while the encoder is below
+/// `setpoint - hysteresis` and at `-relay_pwm` while it is above `setpoint + hysteresis`, which
+/// sets up a small oscillation around the setpoint. Positive PWM must move the encoder upwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayConfig {
+    pub setpoint: f32,
+    /// Magnitude of the relay output, in PWM units
+    pub relay_pwm: f32,
+    /// Half-width of the relay's dead band, in encoder units
+    pub hysteresis: f32,
+    /// How far from the setpoint the encoder may stray before the experiment is aborted
+    pub max_excursion: f32,
+    pub sample_period: Duration,
+    /// Oscillations to let pass before measuring, while the mechanism reaches the setpoint
+    pub settle_cycles: usize,
+    /// Oscillations to average over
+    pub cycles: usize,
+    /// Abort if the measurement has not completed by then
+    pub timeout: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            setpoint: 0.0,
+            relay_pwm: 32.0,
+            hysteresis: 1.0,
+            max_excursion: 100.0,
+            sample_period: Duration::from_millis(10),
+            settle_cycles: 2,
+            cycles: 4,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Rules for turning the ultimate gain and period into PID gains
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TuningRule {
+    ZieglerNichols,
+    ZieglerNicholsPI,
+    TyreusLuyben,
+    SomeOvershoot,
+    NoOvershoot,
+}
+
+impl TuningRule {
+    pub const ALL: [TuningRule; 5] = [
+        TuningRule::ZieglerNichols,
+        TuningRule::ZieglerNicholsPI,
+        TuningRule::TyreusLuyben,
+        TuningRule::SomeOvershoot,
+        TuningRule::NoOvershoot,
+    ];
+
+    /// Proportional gain, integral time and derivative time, as fractions of Ku and Tu
+    fn coefficients(self) -> (f32, f32, f32) {
+        match self {
+            TuningRule::ZieglerNichols => (0.6, 0.5, 0.125),
+            TuningRule::ZieglerNicholsPI => (0.45, 1.0 / 1.2, 0.0),
+            TuningRule::TyreusLuyben => (1.0 / 2.2, 2.2, 1.0 / 6.3),
+            TuningRule::SomeOvershoot => (1.0 / 3.0, 0.5, 1.0 / 3.0),
+            TuningRule::NoOvershoot => (0.2, 0.5, 1.0 / 3.0),
+        }
+    }
+}
+
+/// A set of gains for the device's PID loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+impl PidGains {
+    /// Messages that load these gains into a device
+    pub fn messages(&self) -> [AIMCMessage; 3] {
+        [
+            AIMCMessage::SetKp(self.kp),
+            AIMCMessage::SetKi(self.ki),
+            AIMCMessage::SetKd(self.kd),
+        ]
+    }
+}
+
+/// Outcome of a relay-feedback experiment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayResult {
+    /// Proportional gain at which the loop would oscillate, in PWM per encoder unit
+    pub ultimate_gain: f32,
+    /// Period of that oscillation
+    pub ultimate_period: Duration,
+    /// Half the encoder's peak-to-peak swing during the oscillation
+    pub amplitude: f32,
+}
+
+impl RelayResult {
+    /// Gains proposed by the rule, with the integral and derivative gains per second
+    pub fn gains(&self, rule: TuningRule) -> PidGains {
+        let (p, ti, td) = rule.coefficients();
+        let tu = self.ultimate_period.as_secs_f32();
+        let kp = p * self.ultimate_gain;
+        PidGains {
+            kp,
+            ki: kp / (ti * tu),
+            kd: kp * td * tu,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AutotuneError {
+    /// Communication with the device failed
+    Device(Error),
+    /// Zero cycles would leave nothing to measure
+    ZeroCycles,
+    /// The setpoint is outside the device's target limits
+    SetpointOutsideLimits { setpoint: f32, min: f32, max: f32 },
+    /// The encoder strayed outside the permitted range
+    OutOfBounds { encoder: f32, min: f32, max: f32 },
+    /// No steady oscillation was measured before the timeout
+    NoOscillation,
+}
+
+impl fmt::Display for AutotuneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AutotuneError::Device(e) => write!(f, "Device error: {}", e),
+            AutotuneError::ZeroCycles => write!(f, "At least one cycle must be measured"),
+            AutotuneError::SetpointOutsideLimits { setpoint, min, max } => write!(
+                f,
+                "Setpoint {} is outside the target limits {} to {}",
+                setpoint, min, max
+            ),
+            AutotuneError::OutOfBounds { encoder, min, max } => write!(
+                f,
+                "Encoder reached {}, outside the permitted range {} to {}",
+                encoder, min, max
+            ),
+            AutotuneError::NoOscillation => write!(f, "No steady oscillation was measured"),
+        }
+    }
+}
+
+impl error::Error for AutotuneError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AutotuneError::Device(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for AutotuneError {
+    fn from(e: Error) -> Self {
+        AutotuneError::Device(e)
+    }
+}
+
+/// Range the encoder must stay within: the excursion around the setpoint, narrowed by the
+/// device's target limits where those are in effect
+fn permitted_range<T: Transport>(
+    aimc: &mut AIMC<T>,
+    config: &RelayConfig,
+) -> Result<(f32, f32), AutotuneError> {
+    let mut min = config.setpoint - config.max_excursion;
+    let mut max = config.setpoint + config.max_excursion;
+    if let (ParameterValue::Float(limit_min), ParameterValue::Float(limit_max)) = (
+        aimc.read_parameter(Parameter::LimitTargetMin)?,
+        aimc.read_parameter(Parameter::LimitTargetMax)?,
+    ) {
+        // The firmware ignores the limits until max exceeds min
+        if limit_max > limit_min {
+            if config.setpoint < limit_min || config.setpoint > limit_max {
+                return Err(AutotuneError::SetpointOutsideLimits {
+                    setpoint: config.setpoint,
+                    min: limit_min,
+                    max: limit_max,
+                });
+            }
+            min = min.max(limit_min);
+            max = max.min(limit_max);
+        }
+    }
+    Ok((min, max))
+}
+
+/// Run a relay-feedback experiment around the setpoint and measure the ultimate gain and period.
+///
+/// The device is left disabled afterwards, whether the experiment succeeded or not, with its
+/// original control mode and target restored.
+pub fn relay_autotune<T: Transport, C: Clock>(
+    aimc: &mut AIMC<T>,
+    clock: &C,
+    config: &RelayConfig,
+) -> Result<RelayResult, AutotuneError> {
+    if config.cycles == 0 {
+        return Err(AutotuneError::ZeroCycles);
+    }
+    let (min, max) = permitted_range(aimc, config)?;
+    let mode = match aimc.read_parameter(Parameter::Mode)? {
+        ParameterValue::Mode(mode) => mode,
+        _ => ControlMode::PID,
+    };
+    let target = aimc.status()?.target;
+
+    let result = run_relay(aimc, clock, config, min, max);
+
+    // Put the device back the way it was, but stopped
+    let disabled = aimc.write_message(AIMCMessage::Enable(false));
+    let restored = aimc
+        .write_message(mode.message())
+        .and_then(|_| aimc.write_message(AIMCMessage::SetTarget(target)));
+    let result = result?;
+    disabled?;
+    restored?;
+    Ok(result)
+}
+
+fn run_relay<T: Transport, C: Clock>(
+    aimc: &mut AIMC<T>,
+    clock: &C,
+    config: &RelayConfig,
+    min: f32,
+    max: f32,
+) -> Result<RelayResult, AutotuneError> {
+    // Zero output first, so the device does not lurch when enabled in PWM mode
+    aimc.write_message(AIMCMessage::SetTarget(0.0))?;
+    aimc.write_message(AIMCMessage::ModePWM)?;
+    aimc.write_message(AIMCMessage::Enable(true))?;
+
+    let start = clock.now();
+    let mut output = 0.0;
+    // Times at which the relay switched upwards, and the encoder's extremes since each
+    let mut rises: Vec<Duration> = Vec::new();
+    let mut swings: Vec<f32> = Vec::new();
+    let (mut low, mut high) = (f32::INFINITY, f32::NEG_INFINITY);
+
+    while clock.now() - start < config.timeout {
+        let encoder = aimc.status()?.encoder;
+        if encoder < min || encoder > max {
+            return Err(AutotuneError::OutOfBounds { encoder, min, max });
+        }
+        low = low.min(encoder);
+        high = high.max(encoder);
+
+        let next = if encoder < config.setpoint - config.hysteresis {
+            config.relay_pwm
+        } else if encoder > config.setpoint + config.hysteresis {
+            -config.relay_pwm
+        } else if output == 0.0 {
+            // Starting inside the dead band; drive towards the setpoint to get going
+            if encoder < config.setpoint {
+                config.relay_pwm
+            } else {
+                -config.relay_pwm
+            }
+        } else {
+            output
+        };
+        if next != output {
+            if next > 0.0 {
+                if !rises.is_empty() {
+                    swings.push(high - low);
+                }
+                rises.push(clock.now());
+                low = encoder;
+                high = encoder;
+            }
+            aimc.write_message(AIMCMessage::SetTarget(next))?;
+            output = next;
+        }
+
+        let wanted = config.settle_cycles + config.cycles;
+        if swings.len() >= wanted {
+            let measured = &rises[rises.len() - config.cycles - 1..];
+            let period = (measured[config.cycles] - measured[0]) / config.cycles as u32;
+            let amplitude = swings[swings.len() - config.cycles..].iter().sum::<f32>()
+                / config.cycles as f32
+                / 2.0;
+            // Describing function of a relay with hysteresis
+            let effective = (amplitude.powi(2) - config.hysteresis.powi(2))
+                .max(0.0)
+                .sqrt();
+            if effective <= 0.0 {
+                return Err(AutotuneError::NoOscillation);
+            }
+            return Ok(RelayResult {
+                ultimate_gain: 4.0 * config.relay_pwm / (PI * effective),
+                ultimate_period: period,
+                amplitude,
+            });
+        }
+
+        clock.sleep(config.sample_period);
+    }
+    Err(AutotuneError::NoOscillation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceConfig, ManualClock, PlantModel, SimulatedAIMC};
+
+    fn simulated() -> (AIMC<SimulatedAIMC<ManualClock>>, ManualClock) {
+        let clock = ManualClock::new();
+        let device = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        (AIMC::from_transport(device), clock)
+    }
+
+    #[test]
+    fn test_relay_autotune() {
+        let (mut aimc, clock) = simulated();
+        aimc.write_message(AIMCMessage::SetTarget(7.0)).unwrap();
+        let config = RelayConfig {
+            setpoint: 50.0,
+            ..Default::default()
+        };
+        let result = relay_autotune(&mut aimc, &clock, &config).unwrap();
+        assert!(result.ultimate_gain > 0.0, "{:?}", result);
+        assert!(
+            result.ultimate_period > Duration::from_millis(20),
+            "{:?}",
+            result
+        );
+
+        // Left stopped, in its original mode and with its original target
+        let device = aimc.transport().config();
+        assert!(!device.enabled);
+        assert_eq!(device.mode, ControlMode::PID);
+        assert_eq!(aimc.status().unwrap().target, 7.0);
+
+        // Every rule's gains should bring the mechanism to a new setpoint
+        for rule in TuningRule::ALL.iter() {
+            let (mut aimc, clock) = simulated();
+            for message in result.gains(*rule).messages().iter() {
+                aimc.write_message(*message).unwrap();
+            }
+            aimc.write_message(AIMCMessage::SetTarget(50.0)).unwrap();
+            aimc.write_message(AIMCMessage::Enable(true)).unwrap();
+            clock.advance(Duration::from_secs(10));
+            let status = aimc.status().unwrap();
+            assert!(
+                (status.encoder - 50.0).abs() < 1.0,
+                "{:?}: {:?}",
+                rule,
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn test_start_at_setpoint() {
+        // The encoder starts at zero, inside the dead band
+        let (mut aimc, clock) = simulated();
+        let config = RelayConfig {
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let result = relay_autotune(&mut aimc, &clock, &config).unwrap();
+        assert!(result.ultimate_gain > 0.0, "{:?}", result);
+
+        let config = RelayConfig {
+            cycles: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            relay_autotune(&mut aimc, &clock, &config),
+            Err(AutotuneError::ZeroCycles)
+        ));
+    }
+
+    #[test]
+    fn test_setpoint_outside_limits() {
+        let (mut aimc, clock) = simulated();
+        aimc.write_message(AIMCMessage::LimitTargetMin(0.0))
+            .unwrap();
+        aimc.write_message(AIMCMessage::LimitTargetMax(20.0))
+            .unwrap();
+        let config = RelayConfig {
+            setpoint: 50.0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            relay_autotune(&mut aimc, &clock, &config),
+            Err(AutotuneError::SetpointOutsideLimits { .. })
+        ));
+        assert_eq!(
+            aimc.transport().config(),
+            DeviceConfig {
+                limit_target_min: 0.0,
+                limit_target_max: 20.0,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_abort_disables() {
+        let (mut aimc, clock) = simulated();
+        // Reversed polarity makes the relay drive away from the setpoint
+        aimc.write_message(AIMCMessage::EncoderPolarity(true))
+            .unwrap();
+        aimc.write_message(AIMCMessage::LimitTargetMin(-20.0))
+            .unwrap();
+        aimc.write_message(AIMCMessage::LimitTargetMax(20.0))
+            .unwrap();
+        let config = RelayConfig {
+            setpoint: 10.0,
+            ..Default::default()
+        };
+        match relay_autotune(&mut aimc, &clock, &config) {
+            Err(AutotuneError::OutOfBounds { min, max, .. }) => {
+                assert_eq!((min, max), (-20.0, 20.0));
+            }
+            other => panic!("Expected to abort out of bounds, got {:?}", other),
+        }
+        let device = aimc.transport().config();
+        assert!(!device.enabled);
+        assert_eq!(device.mode, ControlMode::PID);
+    }
+}
@@ -0,0 +1,460 @@
+// Reflashing AIMCs in place through a twiboot-style I2C bootloader
+use crate::{AIMCMessage, Clock, Error, FirmwareImage, Transport, AIMC};
+use std::time::Duration;
+use std::{error, fmt};
+
+/// Address the bootloader answers at, whatever the application's address
+pub const BOOTLOADER_ADDRESS: u16 = 0x29;
+
+/// Time allowed for a device to reset into its bootloader
+pub const BOOTLOADER_STARTUP: Duration = Duration::from_millis(100);
+
+const CMD_WAIT: u8 = 0x00;
+const CMD_READ_VERSION: u8 = 0x01;
+const CMD_ACCESS_MEMORY: u8 = 0x02;
+/// Shares its opcode with reading the version, told apart by a trailing boot type
+const CMD_SWITCH_APPLICATION: u8 = CMD_READ_VERSION;
+const BOOTTYPE_APPLICATION: u8 = 0x80;
+const MEMTYPE_CHIPINFO: u8 = 0x00;
+const MEMTYPE_FLASH: u8 = 0x01;
+
+const VERSION_LEN: usize = 16;
+const CHIP_INFO_LEN: usize = 8;
+
+/// Chip details reported by the bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipInfo {
+    pub signature: [u8; 3],
+    /// Bytes per flash page; pages are written whole
+    pub page_size: usize,
+    /// Flash available to the application, below the bootloader
+    pub flash_size: u32,
+    pub eeprom_size: u32,
+}
+
+impl Default for ChipInfo {
+    /// An ATmega328P with a 1KiB bootloader
+    fn default() -> Self {
+        Self {
+            signature: [0x1E, 0x95, 0x0F],
+            page_size: 128,
+            flash_size: 0x7C00,
+            eeprom_size: 1024,
+        }
+    }
+}
+
+impl ChipInfo {
+    fn from_bytes(bytes: [u8; CHIP_INFO_LEN]) -> Self {
+        Self {
+            signature: [bytes[0], bytes[1], bytes[2]],
+            page_size: usize::from(bytes[3]),
+            flash_size: u32::from(u16::from_be_bytes([bytes[4], bytes[5]])),
+            eeprom_size: u32::from(u16::from_be_bytes([bytes[6], bytes[7]])),
+        }
+    }
+
+    fn into_bytes(self) -> [u8; CHIP_INFO_LEN] {
+        let flash = (self.flash_size as u16).to_be_bytes();
+        let eeprom = (self.eeprom_size as u16).to_be_bytes();
+        let [s0, s1, s2] = self.signature;
+        [
+            s0,
+            s1,
+            s2,
+            self.page_size as u8,
+            flash[0],
+            flash[1],
+            eeprom[0],
+            eeprom[1],
+        ]
+    }
+}
+
+/// Connection to a device's bootloader
+pub struct Bootloader<T> {
+    transport: T,
+}
+
+impl<T: Transport> Bootloader<T> {
+    /// Talk to a bootloader over the transport, which must be bound to `BOOTLOADER_ADDRESS`
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Keep the bootloader from timing out and starting the application
+    pub fn wait(&mut self) -> Result<(), Error> {
+        self.transport.write(&[CMD_WAIT])
+    }
+
+    pub fn version(&mut self) -> Result<String, Error> {
+        self.transport.write(&[CMD_READ_VERSION])?;
+        let mut buffer = [0u8; VERSION_LEN];
+        self.transport.read(&mut buffer)?;
+        let version = String::from_utf8_lossy(&buffer);
+        Ok(version.trim_end_matches(char::from(0)).to_string())
+    }
+
+    pub fn chip_info(&mut self) -> Result<ChipInfo, Error> {
+        self.transport
+            .write(&[CMD_ACCESS_MEMORY, MEMTYPE_CHIPINFO, 0, 0])?;
+        let mut buffer = [0u8; CHIP_INFO_LEN];
+        self.transport.read(&mut buffer)?;
+        Ok(ChipInfo::from_bytes(buffer))
+    }
+
+    /// Program a whole flash page at a page-aligned address
+    pub fn write_page(&mut self, address: u16, page: &[u8]) -> Result<(), Error> {
+        let mut command = vec![CMD_ACCESS_MEMORY, MEMTYPE_FLASH];
+        command.extend_from_slice(&address.to_be_bytes());
+        command.extend_from_slice(page);
+        self.transport.write(&command)
+    }
+
+    pub fn read_flash(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        let [high, low] = address.to_be_bytes();
+        self.transport
+            .write(&[CMD_ACCESS_MEMORY, MEMTYPE_FLASH, high, low])?;
+        self.transport.read(buffer)
+    }
+
+    /// Leave the bootloader and run the application
+    pub fn start_application(&mut self) -> Result<(), Error> {
+        self.transport
+            .write(&[CMD_SWITCH_APPLICATION, BOOTTYPE_APPLICATION])
+    }
+}
+
+/// Ask the application to reset into its bootloader, and give the bootloader time to start.
+/// Follow up promptly with `Bootloader::wait`, before the bootloader gives up and restarts the
+/// application.
+pub fn enter_bootloader<T: Transport, C: Clock>(
+    aimc: &mut AIMC<T>,
+    clock: &C,
+) -> Result<(), Error> {
+    aimc.write_message(AIMCMessage::EnterBootloader)?;
+    clock.sleep(BOOTLOADER_STARTUP);
+    Ok(())
+}
+
+/// Write every page of the image, reading each back to verify it, then start the application.
+/// `progress` is called with the number of pages done and the total after each page.
+///
+/// On failure the device is left in its bootloader, so flashing can be retried.
+pub fn flash_firmware<T: Transport, F: FnMut(usize, usize)>(
+    bootloader: &mut Bootloader<T>,
+    image: &FirmwareImage,
+    mut progress: F,
+) -> Result<ChipInfo, FlashError> {
+    if image.is_empty() {
+        return Err(FlashError::EmptyImage);
+    }
+    bootloader.wait()?;
+    let info = bootloader.chip_info()?;
+    if info.page_size == 0 {
+        return Err(FlashError::InvalidPageSize);
+    }
+    if image.end() > info.flash_size {
+        return Err(FlashError::ImageTooLarge {
+            end: image.end(),
+            flash_size: info.flash_size,
+        });
+    }
+
+    let pages = image.pages(info.page_size);
+    let mut readback = vec![0u8; info.page_size];
+    for (index, (address, page)) in pages.iter().enumerate() {
+        // Within the flash size, so within the bootloader's 16-bit addresses
+        let address = *address as u16;
+        bootloader.write_page(address, page)?;
+        bootloader.read_flash(address, &mut readback)?;
+        if let Some(offset) = page.iter().zip(&readback).position(|(a, b)| a != b) {
+            return Err(FlashError::Verify {
+                address: u32::from(address) + offset as u32,
+                expected: page[offset],
+                actual: readback[offset],
+            });
+        }
+        progress(index + 1, pages.len());
+    }
+
+    bootloader.start_application()?;
+    Ok(info)
+}
+
+#[derive(Debug)]
+pub enum FlashError {
+    /// Communication with the bootloader failed
+    Device(Error),
+    EmptyImage,
+    /// The bootloader reported a page size of zero, so the image cannot be split into pages
+    InvalidPageSize,
+    /// The image extends past the flash available to the application
+    ImageTooLarge {
+        end: u32,
+        flash_size: u32,
+    },
+    /// Flash read back differently from what was written
+    Verify {
+        address: u32,
+        expected: u8,
+        actual: u8,
+    },
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashError::Device(e) => write!(f, "Bootloader error: {}", e),
+            FlashError::EmptyImage => write!(f, "Firmware image is empty"),
+            FlashError::InvalidPageSize => write!(f, "Bootloader reported a page size of 0"),
+            FlashError::ImageTooLarge { end, flash_size } => write!(
+                f,
+                "Image ends at {:#06x}, past the {:#06x} bytes of flash available",
+                end, flash_size
+            ),
+            FlashError::Verify {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Verification failed at {:#06x}: wrote {:#04x}, read {:#04x}",
+                address, expected, actual
+            ),
+        }
+    }
+}
+
+impl error::Error for FlashError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FlashError::Device(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for FlashError {
+    fn from(e: Error) -> Self {
+        FlashError::Device(e)
+    }
+}
+
+/// Read the bootloader has been asked for
+#[derive(Debug, Clone, Copy)]
+enum PendingRead {
+    Version,
+    ChipInfo,
+    Flash(usize),
+}
+
+/// In-memory bootloader. Speaks the device side of the bootloader protocol over the `Transport`
+/// trait, programming and reading back an emulated flash.
+#[derive(Debug, Clone)]
+pub struct SimulatedBootloader {
+    info: ChipInfo,
+    flash: Vec<u8>,
+    pending: Option<PendingRead>,
+    application_started: bool,
+    bad_pages: Vec<usize>,
+}
+
+impl Default for SimulatedBootloader {
+    fn default() -> Self {
+        Self::new(ChipInfo::default())
+    }
+}
+
+impl SimulatedBootloader {
+    /// Bootloader for a chip with erased flash
+    pub fn new(info: ChipInfo) -> Self {
+        Self {
+            info,
+            flash: vec![crate::ERASED_BYTE; info.flash_size as usize],
+            pending: None,
+            application_started: false,
+            bad_pages: Vec::new(),
+        }
+    }
+
+    /// Emulate worn flash, where writes to the page holding the address do not stick
+    pub fn with_bad_page(mut self, address: usize) -> Self {
+        self.bad_pages.push(address / self.info.page_size);
+        self
+    }
+
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Whether the host has told the bootloader to run the application
+    pub fn application_started(&self) -> bool {
+        self.application_started
+    }
+
+    fn reject(&self, data: &[u8]) -> Error {
+        Error::Transport(format!("Bootloader rejected {:02x?}", data).into())
+    }
+}
+
+impl Transport for SimulatedBootloader {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.application_started {
+            return Err(Error::NoDevice {
+                address: BOOTLOADER_ADDRESS,
+            });
+        }
+        self.pending = None;
+        match data {
+            [CMD_WAIT] => (),
+            [CMD_READ_VERSION] => self.pending = Some(PendingRead::Version),
+            [CMD_SWITCH_APPLICATION, BOOTTYPE_APPLICATION] => self.application_started = true,
+            [CMD_ACCESS_MEMORY, MEMTYPE_CHIPINFO, 0, 0] => {
+                self.pending = Some(PendingRead::ChipInfo)
+            }
+            [CMD_ACCESS_MEMORY, MEMTYPE_FLASH, high, low, page @ ..] => {
+                let address = usize::from(u16::from_be_bytes([*high, *low]));
+                if page.is_empty() {
+                    self.pending = Some(PendingRead::Flash(address));
+                    return Ok(());
+                }
+                let page_size = self.info.page_size;
+                if page.len() != page_size
+                    || address % page_size != 0
+                    || address + page_size > self.flash.len()
+                {
+                    return Err(self.reject(data));
+                }
+                if !self.bad_pages.contains(&(address / page_size)) {
+                    self.flash[address..address + page_size].copy_from_slice(page);
+                }
+            }
+            _ => return Err(self.reject(data)),
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        match self.pending {
+            Some(PendingRead::Version) => {
+                let version = b"TWIBOOT v3.0 sim";
+                let len = buffer.len().min(version.len());
+                buffer[..len].copy_from_slice(&version[..len]);
+            }
+            Some(PendingRead::ChipInfo) if buffer.len() == CHIP_INFO_LEN => {
+                buffer.copy_from_slice(&self.info.into_bytes())
+            }
+            Some(PendingRead::Flash(address)) if address + buffer.len() <= self.flash.len() => {
+                buffer.copy_from_slice(&self.flash[address..address + buffer.len()]);
+                // Successive reads carry on where the last left off
+                self.pending = Some(PendingRead::Flash(address + buffer.len()));
+            }
+            _ => return Err(self.reject(&[])),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, MemoryTransport};
+
+    fn image(bytes: &[(u32, u8)]) -> FirmwareImage {
+        let mut hex = String::new();
+        for (address, byte) in bytes {
+            let record = [1, (*address >> 8) as u8, *address as u8, 0, *byte];
+            let checksum = record.iter().fold(0u8, |sum, b| sum.wrapping_sub(*b));
+            hex += ":";
+            for b in record.iter().chain(Some(&checksum)) {
+                hex += &format!("{:02X}", b);
+            }
+            hex += "\n";
+        }
+        hex += ":00000001FF\n";
+        FirmwareImage::from_intel_hex(&hex).unwrap()
+    }
+
+    #[test]
+    fn test_enter_bootloader() {
+        let clock = ManualClock::new();
+        let mut aimc = AIMC::from_transport(MemoryTransport::new());
+        enter_bootloader(&mut aimc, &clock).unwrap();
+        assert_eq!(
+            aimc.transport().writes(),
+            &[AIMCMessage::EnterBootloader.into_bytes().to_vec()]
+        );
+        assert_eq!(clock.now(), BOOTLOADER_STARTUP);
+    }
+
+    #[test]
+    fn test_flash() {
+        let mut bootloader = Bootloader::new(SimulatedBootloader::default());
+        assert_eq!(bootloader.version().unwrap(), "TWIBOOT v3.0 sim");
+        assert_eq!(bootloader.chip_info().unwrap(), ChipInfo::default());
+
+        let image = image(&[(0x0000, 0x0C), (0x0001, 0x94), (0x0102, 0xAB)]);
+        let mut reported = Vec::new();
+        flash_firmware(&mut bootloader, &image, |done, total| {
+            reported.push((done, total))
+        })
+        .unwrap();
+        assert_eq!(reported, vec![(1, 2), (2, 2)]);
+
+        let device = bootloader.into_transport();
+        assert!(device.application_started());
+        assert_eq!(&device.flash()[..3], &[0x0C, 0x94, crate::ERASED_BYTE]);
+        assert_eq!(device.flash()[0x102], 0xAB);
+    }
+
+    #[test]
+    fn test_flash_errors() {
+        let image = image(&[(0x0000, 0x0C), (0x0102, 0xAB)]);
+        let device = SimulatedBootloader::default().with_bad_page(0x0100);
+        let mut bootloader = Bootloader::new(device);
+        match flash_firmware(&mut bootloader, &image, |_, _| ()) {
+            Err(FlashError::Verify {
+                address: 0x0102,
+                expected: 0xAB,
+                actual: crate::ERASED_BYTE,
+            }) => (),
+            other => panic!("Expected verification to fail, got {:?}", other),
+        }
+        // Still in the bootloader, ready to try again
+        assert!(!bootloader.into_transport().application_started());
+
+        let small = ChipInfo {
+            flash_size: 0x0100,
+            ..Default::default()
+        };
+        let mut bootloader = Bootloader::new(SimulatedBootloader::new(small));
+        assert!(matches!(
+            flash_firmware(&mut bootloader, &image, |_, _| ()),
+            Err(FlashError::ImageTooLarge {
+                end: 0x0103,
+                flash_size: 0x0100
+            })
+        ));
+
+        // A garbled chip info read must not be used to split the image
+        let garbled = ChipInfo {
+            page_size: 0,
+            ..Default::default()
+        };
+        let mut bootloader = Bootloader::new(SimulatedBootloader::new(garbled));
+        assert!(matches!(
+            flash_firmware(&mut bootloader, &image, |_, _| ()),
+            Err(FlashError::InvalidPageSize)
+        ));
+        assert!(bootloader
+            .into_transport()
+            .flash()
+            .iter()
+            .all(|b| *b == crate::ERASED_BYTE));
+    }
+}
@@ -0,0 +1,239 @@
+// Whole-bus access, for talking to devices at more than one address
+use crate::transport::{i2c_read, i2c_write};
+use crate::{BusLock, Error, Status, StatusFlags, Transport};
+use i2cdev::linux::LinuxI2CDevice;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// 7-bit addresses that may be assigned to devices. The rest are reserved by the I2C
+/// specification.
+pub const SCAN_ADDRESSES: RangeInclusive<u16> = 0x08..=0x77;
+
+/// A bus on which each transaction may be addressed to a different device.
+pub trait Bus {
+    /// Write the entire buffer to the device at the address
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error>;
+
+    /// Fill the buffer with bytes read from the device at the address
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Error>;
+}
+
+/// Linux I2C character device (`/dev/i2c-*`), rebound to each transaction's address.
+///
+/// Holds the bus's lock for as long as it is open.
+pub struct I2CBus {
+    device: LinuxI2CDevice,
+    path: PathBuf,
+    address: u16,
+    _lock: BusLock,
+}
+
+impl I2CBus {
+    /// Open the specified I2C device file.
+    pub fn open<P: AsRef<Path>>(i2c_device_file: P) -> Result<Self, Error> {
+        let path = i2c_device_file.as_ref();
+        let lock = BusLock::acquire(path)?;
+        // Bound to the general call address until the first transaction
+        let device = LinuxI2CDevice::new(path, 0).map_err(|e| Error::Open {
+            path: path.to_path_buf(),
+            source: e.into(),
+        })?;
+        Ok(Self {
+            device,
+            path: path.to_path_buf(),
+            address: 0,
+            _lock: lock,
+        })
+    }
+
+    /// Path of the device file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn bind(&mut self, address: u16) -> Result<(), Error> {
+        if self.address != address {
+            self.device
+                .set_slave_address(address)
+                .map_err(|e| Error::from_io(e.into(), address))?;
+            self.address = address;
+        }
+        Ok(())
+    }
+}
+
+impl Bus for I2CBus {
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+        self.bind(address)?;
+        i2c_write(&self.device, address, data)
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        self.bind(address)?;
+        i2c_read(&self.device, address, buffer)
+    }
+}
+
+/// In-memory bus. Each address is served by its own transport; the rest do not acknowledge.
+#[derive(Default)]
+pub struct MemoryBus {
+    devices: BTreeMap<u16, Box<dyn Transport + Send>>,
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach a device at the address, replacing any already there
+    pub fn attach<T: Transport + Send + 'static>(&mut self, address: u16, device: T) {
+        self.devices.insert(address, Box::new(device));
+    }
+
+    fn device(&mut self, address: u16) -> Result<&mut Box<dyn Transport + Send>, Error> {
+        self.devices
+            .get_mut(&address)
+            .ok_or(Error::NoDevice { address })
+    }
+}
+
+impl Bus for MemoryBus {
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+        self.device(address)?.write(data)
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        self.device(address)?.read(buffer)
+    }
+}
+
+impl<B: Bus + ?Sized> Bus for &mut B {
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+        (**self).write(address, data)
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        (**self).read(address, buffer)
+    }
+}
+
+/// A single device on a bus, usable wherever a `Transport` is expected.
+pub struct BusDevice<B> {
+    bus: B,
+    address: u16,
+}
+
+impl<B: Bus> BusDevice<B> {
+    pub fn new(bus: B, address: u16) -> Self {
+        Self { bus, address }
+    }
+
+    /// Address of the device
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+}
+
+impl<B: Bus> Transport for BusDevice<B> {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.bus.write(self.address, data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.bus.read(self.address, buffer)
+    }
+}
+
+/// A device that answered a scan like an AIMC
+#[derive(Debug, Clone, Copy)]
+pub struct Discovered {
+    pub address: u16,
+    pub status: Status,
+}
+
+/// Whether a status looks like one an AIMC could have sent, rather than another device's bytes.
+fn is_plausible(bytes: &[u8; 16], status: &Status) -> bool {
+    let flags = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+    status.encoder.is_finite()
+        && status.target.is_finite()
+        && status.pid_out.is_finite()
+        && StatusFlags::from_word(flags).is_some()
+}
+
+/// Probe every assignable address on the bus, returning those that respond like an AIMC.
+pub fn scan<B: Bus>(bus: &mut B) -> Result<Vec<Discovered>, Error> {
+    let mut discovered = Vec::new();
+    for address in SCAN_ADDRESSES {
+        let mut buffer = [0u8; 16];
+        match bus.read(address, &mut buffer) {
+            Ok(()) => {
+                let status = Status::from_bytes(buffer);
+                if is_plausible(&buffer, &status) {
+                    discovered.push(Discovered { address, status });
+                }
+            }
+            // Without the bus there is nothing left to probe
+            Err(e @ Error::Open { .. }) | Err(e @ Error::BusInUse { .. }) => return Err(e),
+            // Anything else on the bus is not our concern, however it behaves. Many adapters
+            // report an empty address as a plain I/O error rather than a NACK.
+            Err(_) => (),
+        }
+    }
+    Ok(discovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AIMCMessage, ManualClock, MemoryTransport, PlantModel, SimulatedAIMC, AIMC};
+
+    /// Fails every transaction the way some adapters do for an empty address
+    struct Failing;
+
+    fn eio() -> Error {
+        Error::Bus(std::io::Error::from_raw_os_error(
+            nix::errno::Errno::EIO as i32,
+        ))
+    }
+
+    impl Transport for Failing {
+        fn write(&mut self, _data: &[u8]) -> Result<(), Error> {
+            Err(eio())
+        }
+
+        fn read(&mut self, _buffer: &mut [u8]) -> Result<(), Error> {
+            Err(eio())
+        }
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut bus = MemoryBus::new();
+        let clock = ManualClock::new();
+        bus.attach(
+            0x10,
+            SimulatedAIMC::with_clock(PlantModel::default(), clock.clone()),
+        );
+        bus.attach(
+            0x42,
+            SimulatedAIMC::with_clock(PlantModel::default(), clock.clone()),
+        );
+        // Some other device answering with bytes that are not a status
+        let mut eeprom = MemoryTransport::new();
+        eeprom.queue_read(&[0xFF; 16]);
+        bus.attach(0x50, eeprom);
+        // A device answering outside the assignable range is never probed
+        bus.attach(0x03, MemoryTransport::new());
+        // An address the adapter reports EIO for
+        bus.attach(0x20, Failing);
+
+        let mut aimc = AIMC::from_transport(BusDevice::new(&mut bus, 0x42));
+        aimc.write_message(AIMCMessage::SetTarget(3.0)).unwrap();
+
+        let discovered = scan(&mut bus).unwrap();
+        let addresses: Vec<_> = discovered.iter().map(|d| d.address).collect();
+        assert_eq!(addresses, vec![0x10, 0x42]);
+        assert_eq!(discovered[1].status.target, 3.0);
+    }
+}
@@ -0,0 +1,473 @@
+// Recording bus traffic to a file, and replaying it in place of a device
+use crate::{Bus, Clock, Error, SystemClock, Transport};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use std::{error, fmt};
+
+/// First line of every capture file
+pub const CAPTURE_HEADER: &str = "# aimc capture v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Host to device
+    Write,
+    /// Device to host
+    Read,
+}
+
+/// A single transaction, as stored in a capture file.
+///
+/// Each is one line: seconds since the capture began, bus, address, `W` or `R`, then the bytes
+/// in hex. For example, `1.250000 /dev/i2c-1 0x10 W 0200002041`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureRecord {
+    pub time: Duration,
+    pub bus: String,
+    pub address: u16,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for CaptureRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06} {} {:#04x} {} ",
+            self.time.as_secs(),
+            self.time.subsec_micros(),
+            self.bus,
+            self.address,
+            match self.direction {
+                Direction::Write => 'W',
+                Direction::Read => 'R',
+            }
+        )?;
+        for byte in &self.data {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode a string of hex digit pairs
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl FromStr for CaptureRecord {
+    type Err = CaptureParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_whitespace();
+        let mut field = |name: &'static str| fields.next().ok_or(CaptureParseError::Missing(name));
+        let time = field("time")?;
+        let time = time
+            .parse::<f64>()
+            .ok()
+            .filter(|t| *t >= 0.0 && t.is_finite())
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| CaptureParseError::Invalid("time", time.to_string()))?;
+        let bus = field("bus")?.to_string();
+        let address = field("address")?;
+        let address = u16::from_str_radix(address.trim_start_matches("0x"), 16)
+            .map_err(|_| CaptureParseError::Invalid("address", address.to_string()))?;
+        let direction = match field("direction")? {
+            "W" => Direction::Write,
+            "R" => Direction::Read,
+            other => return Err(CaptureParseError::Invalid("direction", other.to_string())),
+        };
+        // An empty transfer has no data field
+        let data = fields.next().unwrap_or("");
+        let data =
+            parse_hex(data).ok_or_else(|| CaptureParseError::Invalid("data", data.to_string()))?;
+        Ok(Self {
+            time,
+            bus,
+            address,
+            direction,
+            data,
+        })
+    }
+}
+
+/// Read every record from a capture, skipping comments and blank lines
+pub fn read_capture<R: BufRead>(reader: R) -> Result<Vec<CaptureRecord>, CaptureError> {
+    let mut records = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(CaptureError::Io)?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = line.parse().map_err(|error| CaptureError::Parse {
+            line: index + 1,
+            error,
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Destination for recorded transactions. Clones share the destination and the clock, so one
+/// capture can collect the traffic of every device in a process.
+#[derive(Clone)]
+pub struct Capture {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+    clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl Capture {
+    /// Record to the writer, timestamped from now
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<Self> {
+        Self::with_clock(writer, SystemClock::new())
+    }
+
+    /// Record to the writer, timestamped by the clock
+    pub fn with_clock<W, C>(writer: W, clock: C) -> io::Result<Self>
+    where
+        W: Write + Send + 'static,
+        C: Clock + Send + Sync + 'static,
+    {
+        let mut sink: Box<dyn Write + Send> = Box::new(writer);
+        writeln!(sink, "{}", CAPTURE_HEADER)?;
+        Ok(Self {
+            sink: Arc::new(Mutex::new(sink)),
+            clock: Arc::new(clock),
+        })
+    }
+
+    /// Record to a new file at the path, replacing any already there
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Append a transaction. Whitespace in the bus name is replaced, to keep the line parseable.
+    pub fn record(&self, bus: &str, address: u16, direction: Direction, data: &[u8]) {
+        let record = CaptureRecord {
+            time: self.clock.now(),
+            bus: bus.split_whitespace().collect::<Vec<_>>().join("_"),
+            address,
+            direction,
+            data: data.to_vec(),
+        };
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        // Losing the capture must never take the device down with it
+        let _ = writeln!(sink, "{}", record).and_then(|_| sink.flush());
+    }
+}
+
+/// Transport that records every successful transaction through it
+pub struct CaptureTransport<T> {
+    inner: T,
+    capture: Capture,
+    bus: String,
+    address: u16,
+}
+
+impl<T: Transport> CaptureTransport<T> {
+    /// Record traffic through the transport as the device at the address on the named bus
+    pub fn new(inner: T, capture: Capture, bus: &str, address: u16) -> Self {
+        Self {
+            inner,
+            capture,
+            bus: bus.to_string(),
+            address,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for CaptureTransport<T> {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.inner.write(data)?;
+        self.capture
+            .record(&self.bus, self.address, Direction::Write, data);
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.inner.read(buffer)?;
+        self.capture
+            .record(&self.bus, self.address, Direction::Read, buffer);
+        Ok(())
+    }
+}
+
+/// Bus that records every successful transaction on it
+pub struct CaptureBus<B> {
+    inner: B,
+    capture: Capture,
+    bus: String,
+}
+
+impl<B: Bus> CaptureBus<B> {
+    pub fn new(inner: B, capture: Capture, bus: &str) -> Self {
+        Self {
+            inner,
+            capture,
+            bus: bus.to_string(),
+        }
+    }
+}
+
+impl<B: Bus> Bus for CaptureBus<B> {
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+        self.inner.write(address, data)?;
+        self.capture
+            .record(&self.bus, address, Direction::Write, data);
+        Ok(())
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        self.inner.read(address, buffer)?;
+        self.capture
+            .record(&self.bus, address, Direction::Read, buffer);
+        Ok(())
+    }
+}
+
+/// Stands in for a device by replaying its side of a capture. Reads are served from the
+/// recording, and writes must match it exactly.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayTransport {
+    records: VecDeque<CaptureRecord>,
+}
+
+impl ReplayTransport {
+    /// Replay the records in order
+    pub fn new<I: IntoIterator<Item = CaptureRecord>>(records: I) -> Self {
+        Self {
+            records: records.into_iter().collect(),
+        }
+    }
+
+    /// Replay the traffic of a single device from a capture of a whole session
+    pub fn for_device(records: &[CaptureRecord], bus: &str, address: u16) -> Self {
+        Self::new(
+            records
+                .iter()
+                .filter(|r| r.bus == bus && r.address == address)
+                .cloned(),
+        )
+    }
+
+    /// Transactions recorded but not yet replayed
+    pub fn remaining(&self) -> usize {
+        self.records.len()
+    }
+
+    fn next(&mut self, direction: Direction, data: &[u8]) -> Result<CaptureRecord, Error> {
+        let mismatch = |expected| {
+            Error::Transport(Box::new(ReplayMismatch {
+                expected,
+                direction,
+                data: data.to_vec(),
+            }))
+        };
+        let record = self.records.pop_front().ok_or_else(|| mismatch(None))?;
+        let matches = record.direction == direction
+            && match direction {
+                Direction::Write => record.data == data,
+                Direction::Read => record.data.len() == data.len(),
+            };
+        if !matches {
+            return Err(mismatch(Some(record)));
+        }
+        Ok(record)
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.next(Direction::Write, data).map(drop)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let record = self.next(Direction::Read, buffer)?;
+        buffer.copy_from_slice(&record.data);
+        Ok(())
+    }
+}
+
+/// The host did something other than what was recorded. For reads, `data` holds only as many
+/// zeros as were asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayMismatch {
+    /// Next transaction in the recording, if any remain
+    pub expected: Option<CaptureRecord>,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for ReplayMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Replay diverged at {:?} of {:02x?}: ",
+            self.direction, self.data
+        )?;
+        match &self.expected {
+            Some(record) => write!(f, "expected \"{}\"", record),
+            None => write!(f, "recording has ended"),
+        }
+    }
+}
+
+impl error::Error for ReplayMismatch {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureParseError {
+    /// A field was absent
+    Missing(&'static str),
+    /// A field could not be interpreted
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for CaptureParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureParseError::Missing(field) => write!(f, "Missing {}", field),
+            CaptureParseError::Invalid(field, text) => write!(f, "Invalid {} '{}'", field, text),
+        }
+    }
+}
+
+impl error::Error for CaptureParseError {}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    Parse {
+        line: usize,
+        error: CaptureParseError,
+    },
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Io(e) => write!(f, "Could not read capture: {}", e),
+            CaptureError::Parse { line, error } => write!(f, "Line {}: {}", line, error),
+        }
+    }
+}
+
+impl error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CaptureError::Io(e) => Some(e),
+            CaptureError::Parse { error, .. } => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AIMCMessage, ManualClock, PlantModel, SimulatedAIMC, AIMC};
+
+    /// Writer whose contents can be inspected after it is handed to a capture
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_format() {
+        let record = CaptureRecord {
+            time: Duration::from_micros(1_250_000),
+            bus: "/dev/i2c-1".to_string(),
+            address: 0x10,
+            direction: Direction::Write,
+            data: vec![0x02, 0x00, 0x00, 0x20, 0x41],
+        };
+        let line = record.to_string();
+        assert_eq!(line, "1.250000 /dev/i2c-1 0x10 W 0200002041");
+        assert_eq!(line.parse::<CaptureRecord>().unwrap(), record);
+
+        assert_eq!(
+            "1.0 /dev/i2c-1 0x10 X 00".parse::<CaptureRecord>(),
+            Err(CaptureParseError::Invalid("direction", "X".to_string()))
+        );
+        assert_eq!(
+            "1.0 /dev/i2c-1".parse::<CaptureRecord>(),
+            Err(CaptureParseError::Missing("address"))
+        );
+        assert!(matches!(
+            read_capture("# comment\n\n1.0 bus 0x10 R 0g\n".as_bytes()),
+            Err(CaptureError::Parse { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_capture_and_replay() {
+        let clock = ManualClock::new();
+        let buffer = SharedBuffer::default();
+        let capture = Capture::with_clock(buffer.clone(), clock.clone()).unwrap();
+
+        let simulated = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        let mut aimc = AIMC::from_transport(CaptureTransport::new(
+            simulated,
+            capture,
+            "/dev/i2c-1",
+            0x10,
+        ));
+        fn session<T: Transport>(aimc: &mut AIMC<T>) -> crate::Status {
+            aimc.write_message(AIMCMessage::ModePWM).unwrap();
+            aimc.write_message(AIMCMessage::SetTarget(20.0)).unwrap();
+            aimc.write_message(AIMCMessage::Enable(true)).unwrap();
+            aimc.status().unwrap()
+        }
+        let live = session(&mut aimc);
+        clock.advance(Duration::from_millis(100));
+        let live_later = aimc.status().unwrap();
+
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(text.starts_with(CAPTURE_HEADER));
+        let records = read_capture(text.as_bytes()).unwrap();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[4].time, Duration::from_millis(100));
+
+        let mut replayed =
+            AIMC::from_transport(ReplayTransport::for_device(&records, "/dev/i2c-1", 0x10));
+        assert_eq!(session(&mut replayed).pid_out, live.pid_out);
+        assert_eq!(replayed.status().unwrap().encoder, live_later.encoder);
+        assert_eq!(replayed.transport().remaining(), 0);
+
+        // Diverging from the recording is caught
+        let mut diverged = AIMC::from_transport(ReplayTransport::new(records));
+        diverged.write_message(AIMCMessage::ModePWM).unwrap();
+        match diverged.write_message(AIMCMessage::SetTarget(21.0)) {
+            Err(Error::Transport(e)) => {
+                let mismatch = e.downcast_ref::<ReplayMismatch>().unwrap();
+                assert_eq!(
+                    mismatch.expected.as_ref().unwrap().data,
+                    AIMCMessage::SetTarget(20.0).into_bytes().to_vec()
+                );
+            }
+            other => panic!("Expected a replay mismatch, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// Saving parameters to the device's EEPROM, so they survive a power cycle
+use crate::{AIMCMessage, Clock, DeviceConfig, Error, Parameter, Transport, AIMC};
+use std::time::Duration;
+use std::{error, fmt};
+
+/// Time the firmware needs to write its parameters to EEPROM. It does not answer meanwhile.
+pub const EEPROM_WRITE_TIME: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum SaveError {
+    /// Communication with the device failed
+    Device(Error),
+    /// These parameters read back differently after being saved and loaded again
+    Mismatch(Vec<Parameter>),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Device(e) => write!(f, "Device error: {}", e),
+            SaveError::Mismatch(parameters) => {
+                write!(f, "Parameters not saved correctly: {:?}", parameters)
+            }
+        }
+    }
+}
+
+impl error::Error for SaveError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SaveError::Device(e) => Some(e),
+            SaveError::Mismatch(_) => None,
+        }
+    }
+}
+
+impl From<Error> for SaveError {
+    fn from(e: Error) -> Self {
+        SaveError::Device(e)
+    }
+}
+
+/// Commit the device's current parameters to EEPROM, then load them back to check they were
+/// stored. Returns the configuration that was saved.
+pub fn save_config<T: Transport, C: Clock>(
+    aimc: &mut AIMC<T>,
+    clock: &C,
+) -> Result<DeviceConfig, SaveError> {
+    let config = aimc.read_config()?;
+    aimc.write_message(AIMCMessage::SaveConfig)?;
+    clock.sleep(EEPROM_WRITE_TIME);
+    let saved = load_config(aimc)?;
+    let mismatch: Vec<Parameter> = config
+        .differences(&saved)
+        .into_iter()
+        .filter(|parameter| parameter.is_persisted())
+        .collect();
+    if mismatch.is_empty() {
+        Ok(config)
+    } else {
+        Err(SaveError::Mismatch(mismatch))
+    }
+}
+
+/// Replace the device's parameters with those saved in EEPROM, and read them back. Whether the
+/// device is enabled is left unchanged.
+pub fn load_config<T: Transport>(aimc: &mut AIMC<T>) -> Result<DeviceConfig, Error> {
+    aimc.write_message(AIMCMessage::LoadConfig)?;
+    aimc.read_config()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, PlantModel, SimulatedAIMC};
+
+    #[test]
+    fn test_save_and_load() {
+        let clock = ManualClock::new();
+        let device = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        let mut aimc = AIMC::from_transport(device);
+        aimc.write_message(AIMCMessage::SetKi(0.5)).unwrap();
+        aimc.write_message(AIMCMessage::LimitPwm(100)).unwrap();
+
+        let saved = save_config(&mut aimc, &clock).unwrap();
+        assert_eq!(saved.ki, 0.5);
+        assert_eq!(aimc.transport().saved_config(), saved);
+
+        aimc.write_message(AIMCMessage::LimitPwm(20)).unwrap();
+        assert_eq!(load_config(&mut aimc).unwrap().limit_pwm, 100);
+    }
+}
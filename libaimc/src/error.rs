@@ -0,0 +1,130 @@
+use crate::DecodeError;
+use nix::errno::Errno;
+use std::path::PathBuf;
+use std::{error, fmt, io};
+
+/// Errors arising from communication with an AIMC.
+#[derive(Debug)]
+pub enum Error {
+    /// The bus device file could not be opened or bound to an address
+    Open { path: PathBuf, source: io::Error },
+    /// Another process (or another handle in this one) holds the bus's advisory lock
+    BusInUse { path: PathBuf, pid: Option<u32> },
+    /// Nothing acknowledged the address
+    NoDevice { address: u16 },
+    /// Fewer bytes were read than requested
+    ShortRead { expected: usize, actual: usize },
+    /// Fewer bytes were written than requested
+    ShortWrite { expected: usize, actual: usize },
+    /// The bus did not complete the transaction in time
+    Timeout,
+    /// Any other failure reported by the bus
+    Bus(io::Error),
+    /// The bytes received could not be interpreted
+    Decode(DecodeError),
+    /// Status does not reflect the most recent message sent
+    StaleStatus { expected: u8, actual: u8 },
+    /// Failure specific to a transport backend
+    Transport(Box<dyn error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Interpret an OS-level error from a transaction with the device at `address`
+    pub fn from_io(error: io::Error, address: u16) -> Self {
+        // The kernel's I2C drivers disagree on which errno means an address was not acknowledged
+        match error.raw_os_error().map(Errno::from_i32) {
+            Some(Errno::ENXIO) | Some(Errno::ENODEV) | Some(Errno::EREMOTEIO) => {
+                Error::NoDevice { address }
+            }
+            Some(Errno::ETIMEDOUT) => Error::Timeout,
+            _ => Error::Bus(error),
+        }
+    }
+
+    /// Whether retrying the same transaction may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::ShortRead { .. } | Error::ShortWrite { .. } | Error::Timeout | Error::Bus(_)
+        )
+    }
+
+    /// Whether the device is absent, rather than misbehaving
+    pub fn is_device_missing(&self) -> bool {
+        matches!(self, Error::Open { .. } | Error::NoDevice { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Open { path, source } => {
+                write!(f, "Failed to open {}: {}", path.display(), source)
+            }
+            Error::BusInUse {
+                path,
+                pid: Some(pid),
+            } => {
+                write!(f, "Bus {} in use by pid {}", path.display(), pid)
+            }
+            Error::BusInUse { path, pid: None } => {
+                write!(f, "Bus {} in use by another process", path.display())
+            }
+            Error::NoDevice { address } => write!(f, "No device at address {:#04x}", address),
+            Error::ShortRead { expected, actual } => {
+                write!(f, "Short read: {} of {} bytes", actual, expected)
+            }
+            Error::ShortWrite { expected, actual } => {
+                write!(f, "Short write: {} of {} bytes", actual, expected)
+            }
+            Error::Timeout => write!(f, "Bus timed out"),
+            Error::Bus(e) => write!(f, "Bus error: {}", e),
+            Error::Decode(e) => write!(f, "Protocol error: {}", e),
+            Error::StaleStatus { expected, actual } => write!(
+                f,
+                "Stale status: reflects message {}, expected {}",
+                actual, expected
+            ),
+            Error::Transport(e) => write!(f, "Transport error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Open { source, .. } => Some(source),
+            Error::Bus(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Transport(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io() {
+        let nack = Error::from_io(io::Error::from_raw_os_error(Errno::EREMOTEIO as i32), 0x10);
+        assert!(matches!(nack, Error::NoDevice { address: 0x10 }));
+        assert!(nack.is_device_missing());
+        assert!(!nack.is_transient());
+
+        let timeout = Error::from_io(io::Error::from_raw_os_error(Errno::ETIMEDOUT as i32), 0x10);
+        assert!(matches!(timeout, Error::Timeout));
+        assert!(timeout.is_transient());
+
+        let other = Error::from_io(io::Error::from_raw_os_error(Errno::EIO as i32), 0x10);
+        assert!(matches!(other, Error::Bus(_)));
+        assert!(error::Error::source(&other).is_some());
+    }
+}
@@ -0,0 +1,197 @@
+// Homing to the limit switch, waiting for it to finish
+use crate::{AIMCMessage, Clock, Error, Status, StatusFlags, Transport, AIMC};
+use std::time::Duration;
+use std::{error, fmt};
+
+/// Settings for a homing run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HomingConfig {
+    /// Homing speed in PWM units. Its sign sets the direction, which must lead to the switch.
+    pub speed: i32,
+    /// Give up if the limit switch has not tripped by then
+    pub timeout: Duration,
+    pub poll_period: Duration,
+}
+
+impl Default for HomingConfig {
+    fn default() -> Self {
+        Self {
+            speed: -64,
+            timeout: Duration::from_secs(10),
+            poll_period: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Outcome of a successful homing run
+#[derive(Debug, Clone, Copy)]
+pub struct HomingReport {
+    /// How long the limit switch took to trip
+    pub elapsed: Duration,
+    /// Status once homing had stopped, with the encoder zeroed at the switch
+    pub status: Status,
+}
+
+#[derive(Debug)]
+pub enum HomingError {
+    /// Communication with the device failed
+    Device(Error),
+    /// A speed of zero would never reach the switch
+    ZeroSpeed,
+    /// The homing switch did not trip in time
+    Timeout { elapsed: Duration, status: Status },
+}
+
+impl fmt::Display for HomingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HomingError::Device(e) => write!(f, "Device error: {}", e),
+            HomingError::ZeroSpeed => write!(f, "Homing speed must not be zero"),
+            HomingError::Timeout { elapsed, status } => write!(
+                f,
+                "Limit switch did not trip within {:?}; encoder at {}",
+                elapsed, status.encoder
+            ),
+        }
+    }
+}
+
+impl error::Error for HomingError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HomingError::Device(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for HomingError {
+    fn from(e: Error) -> Self {
+        HomingError::Device(e)
+    }
+}
+
+/// Start homing, wait for the limit switch to trip, then stop homing.
+///
+/// Homing is stopped however the run ends, including on timeout or a communication failure.
+pub fn home<T: Transport, C: Clock>(
+    aimc: &mut AIMC<T>,
+    clock: &C,
+    config: &HomingConfig,
+) -> Result<HomingReport, HomingError> {
+    if config.speed == 0 {
+        return Err(HomingError::ZeroSpeed);
+    }
+    let result = wait_for_switch(aimc, clock, config);
+    let stopped = aimc.write_message(AIMCMessage::Home(0));
+    let elapsed = result?;
+    stopped?;
+    Ok(HomingReport {
+        elapsed,
+        status: aimc.status()?,
+    })
+}
+
+fn wait_for_switch<T: Transport, C: Clock>(
+    aimc: &mut AIMC<T>,
+    clock: &C,
+    config: &HomingConfig,
+) -> Result<Duration, HomingError> {
+    aimc.write_message(AIMCMessage::Home(config.speed))?;
+    let start = clock.now();
+    loop {
+        let status = aimc.status()?;
+        let elapsed = clock.now() - start;
+        // Only the homing switch will do; the far end may be pressed before homing starts
+        if status.flags.contains(StatusFlags::LIMIT_MIN) {
+            // Give the firmware a cycle at the switch to zero the encoder
+            clock.sleep(config.poll_period);
+            return Ok(elapsed);
+        }
+        if elapsed > config.timeout {
+            return Err(HomingError::Timeout { elapsed, status });
+        }
+        clock.sleep(config.poll_period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, MemoryTransport, PlantModel, SimulatedAIMC};
+
+    fn simulated(plant: PlantModel) -> (AIMC<SimulatedAIMC<ManualClock>>, ManualClock) {
+        let clock = ManualClock::new();
+        let device = SimulatedAIMC::with_clock(plant, clock.clone());
+        (AIMC::from_transport(device), clock)
+    }
+
+    #[test]
+    fn test_home() {
+        let (mut aimc, clock) = simulated(PlantModel {
+            travel_min: -50.0,
+            ..Default::default()
+        });
+        aimc.write_message(AIMCMessage::SetTarget(20.0)).unwrap();
+        let report = home(&mut aimc, &clock, &HomingConfig::default()).unwrap();
+        assert!(report.elapsed < Duration::from_secs(1), "{:?}", report);
+        assert_eq!(report.status.encoder, 0.0);
+        assert!(report.status.flags.contains(StatusFlags::LIMIT_MIN));
+
+        // Stopped: the mechanism stays at the switch
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(aimc.status().unwrap().encoder, 0.0);
+        assert_eq!(aimc.transport().position(), -50.0);
+    }
+
+    #[test]
+    fn test_home_from_max_switch() {
+        let status = |encoder, flags| {
+            Status {
+                encoder,
+                target: 0.0,
+                pid_out: -64.0,
+                flags,
+            }
+            .into_bytes()
+        };
+        let mut transport = MemoryTransport::new();
+        transport.queue_read(&status(500.0, StatusFlags::LIMIT_MAX));
+        transport.queue_read(&status(250.0, StatusFlags::HOMING));
+        transport.queue_read(&status(0.0, StatusFlags::LIMIT_MIN));
+        transport.queue_read(&status(0.0, StatusFlags::LIMIT_MIN));
+        let mut aimc = AIMC::from_transport(transport);
+        let clock = ManualClock::new();
+
+        let report = home(&mut aimc, &clock, &HomingConfig::default()).unwrap();
+        assert_eq!(report.elapsed, Duration::from_millis(20));
+        assert_eq!(report.status.encoder, 0.0);
+        assert_eq!(aimc.transport().pending_reads(), 0);
+    }
+
+    #[test]
+    fn test_timeout_stops_homing() {
+        let (mut aimc, clock) = simulated(PlantModel::default());
+        let config = HomingConfig {
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        match home(&mut aimc, &clock, &config) {
+            Err(HomingError::Timeout { elapsed, .. }) => {
+                assert!(elapsed > config.timeout);
+            }
+            other => panic!("Expected homing to time out, got {:?}", other),
+        }
+        // No longer driving, short of the switch
+        clock.advance(Duration::from_millis(10));
+        let status = aimc.status().unwrap();
+        assert_eq!(status.pid_out, 0.0);
+        assert!(!status.flags.at_limit());
+        assert!(!status.flags.contains(StatusFlags::HOMING));
+
+        assert!(matches!(
+            home(&mut aimc, &clock, &HomingConfig { speed: 0, ..config }),
+            Err(HomingError::ZeroSpeed)
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+// Firmware images in Intel HEX format
+use std::collections::BTreeMap;
+use std::{error, fmt};
+
+/// Value of erased flash, used to pad partial pages
+pub const ERASED_BYTE: u8 = 0xFF;
+
+/// Contents of a firmware image, by flash address. Gaps are left unprogrammed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FirmwareImage {
+    bytes: BTreeMap<u32, u8>,
+}
+
+impl FirmwareImage {
+    /// Parse an Intel HEX file, as produced by `avr-objcopy -O ihex`
+    pub fn from_intel_hex(text: &str) -> Result<Self, HexError> {
+        let mut image = Self::default();
+        let mut base = 0u32;
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = line
+                .strip_prefix(':')
+                .and_then(crate::parse_hex)
+                .filter(|r| r.len() >= 5 && r.len() == usize::from(r[0]) + 5)
+                .ok_or(HexError::Syntax { line: line_number })?;
+            if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(HexError::Checksum { line: line_number });
+            }
+
+            let offset = u32::from(u16::from_be_bytes([record[1], record[2]]));
+            let data = &record[4..record.len() - 1];
+            match record[3] {
+                0x00 => {
+                    for (i, byte) in data.iter().enumerate() {
+                        let address = base
+                            .checked_add(offset + i as u32)
+                            .ok_or(HexError::AddressOverflow { line: line_number })?;
+                        image.bytes.insert(address, *byte);
+                    }
+                }
+                0x01 => return Ok(image),
+                // Extended segment address, in 16-byte paragraphs
+                0x02 if data.len() == 2 => {
+                    base = u32::from(u16::from_be_bytes([data[0], data[1]])) << 4
+                }
+                // Extended linear address, the upper 16 bits
+                0x04 if data.len() == 2 => {
+                    base = u32::from(u16::from_be_bytes([data[0], data[1]])) << 16
+                }
+                // Start addresses mean nothing to a bootloader
+                0x03 | 0x05 => (),
+                kind => {
+                    return Err(HexError::UnsupportedRecord {
+                        line: line_number,
+                        kind,
+                    })
+                }
+            }
+        }
+        Err(HexError::MissingEnd)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// One past the highest address in the image
+    pub fn end(&self) -> u32 {
+        self.bytes
+            .keys()
+            .next_back()
+            .map_or(0, |address| address.saturating_add(1))
+    }
+
+    /// Split the image into pages, padding each with erased bytes. Pages the image does not
+    /// touch are left out.
+    pub fn pages(&self, page_size: usize) -> Vec<(u32, Vec<u8>)> {
+        let page_size = page_size as u32;
+        let mut pages: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (address, byte) in &self.bytes {
+            let start = address - address % page_size;
+            match pages.last_mut() {
+                Some((last, page)) if *last == start => {
+                    page[(address - start) as usize] = *byte;
+                }
+                _ => {
+                    let mut page = vec![ERASED_BYTE; page_size as usize];
+                    page[(address - start) as usize] = *byte;
+                    pages.push((start, page));
+                }
+            }
+        }
+        pages
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HexError {
+    /// A line was not a well-formed record
+    Syntax {
+        line: usize,
+    },
+    Checksum {
+        line: usize,
+    },
+    UnsupportedRecord {
+        line: usize,
+        kind: u8,
+    },
+    /// A record's data runs past the end of the 32-bit address space
+    AddressOverflow {
+        line: usize,
+    },
+    /// The file ended without an end-of-file record, so may have been truncated
+    MissingEnd,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexError::Syntax { line } => write!(f, "Malformed record on line {}", line),
+            HexError::Checksum { line } => write!(f, "Checksum mismatch on line {}", line),
+            HexError::UnsupportedRecord { line, kind } => {
+                write!(f, "Unsupported record type {:#04x} on line {}", kind, line)
+            }
+            HexError::AddressOverflow { line } => {
+                write!(f, "Address out of range on line {}", line)
+            }
+            HexError::MissingEnd => write!(f, "No end-of-file record; is the file truncated?"),
+        }
+    }
+}
+
+impl error::Error for HexError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let image = FirmwareImage::from_intel_hex(
+            ":100000000C9434000C943E000C943E000C943E0082\n\
+             :02000004000AF0\n\
+             :0400000001020304F2\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+        assert_eq!(image.end(), 0x000A_0004);
+
+        let pages = image.pages(64);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].0, 0);
+        assert_eq!(&pages[0].1[..4], &[0x0C, 0x94, 0x34, 0x00]);
+        assert_eq!(pages[0].1[16], ERASED_BYTE);
+        assert_eq!(pages[1].0, 0x000A_0000);
+        assert_eq!(&pages[1].1[..5], &[1, 2, 3, 4, ERASED_BYTE]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            FirmwareImage::from_intel_hex(":0400000001020304F3\n:00000001FF"),
+            Err(HexError::Checksum { line: 1 })
+        );
+        assert_eq!(
+            FirmwareImage::from_intel_hex("\n:04000000010203F2"),
+            Err(HexError::Syntax { line: 2 })
+        );
+        assert_eq!(
+            FirmwareImage::from_intel_hex(":0400000001020304F2\n"),
+            Err(HexError::MissingEnd)
+        );
+        assert_eq!(
+            FirmwareImage::from_intel_hex(":00000006FA"),
+            Err(HexError::UnsupportedRecord { line: 1, kind: 6 })
+        );
+        assert_eq!(
+            FirmwareImage::from_intel_hex(":02000004FFFFFC\n:02FFFF000102FD\n:00000001FF"),
+            Err(HexError::AddressOverflow { line: 2 })
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// Background heartbeats, so a device disables itself when the host goes away
+use crate::{AIMCMessage, Error, Transport, AIMC};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Heartbeats sent per watchdog timeout, so that one or two lost to bus errors are survivable
+const HEARTBEATS_PER_TIMEOUT: u32 = 4;
+
+/// Sends heartbeats to a device from a background thread until dropped.
+///
+/// Dropping the keepalive stops the heartbeats but leaves the watchdog armed, so the device
+/// disables itself shortly afterwards.
+pub struct Keepalive {
+    stop: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl Keepalive {
+    /// Arm the device's watchdog with the timeout, then keep it fed
+    pub fn start<T: Transport + Send + 'static>(
+        aimc: Arc<Mutex<AIMC<T>>>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        aimc.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_message(AIMCMessage::SetWatchdogTimeout(timeout.as_millis() as u32))?;
+
+        let interval = (timeout / HEARTBEATS_PER_TIMEOUT).max(Duration::from_millis(1));
+        let (stop, stopped) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
+        let last_error = error.clone();
+        let thread = thread::Builder::new()
+            .name("aimc-keepalive".to_string())
+            .spawn(move || loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => return,
+                }
+                let sent = aimc
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .write_message(AIMCMessage::Heartbeat);
+                if let Err(e) = sent {
+                    *last_error.lock().unwrap_or_else(PoisonError::into_inner) = Some(e);
+                }
+            })
+            .expect("Failed to spawn AIMC keepalive thread");
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+            error,
+        })
+    }
+
+    /// Take the most recent failure to send a heartbeat, if any
+    pub fn take_error(&self) -> Option<Error> {
+        self.error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryTransport;
+    use std::time::Instant;
+
+    #[test]
+    fn test_keepalive() {
+        let aimc = Arc::new(Mutex::new(AIMC::from_transport(MemoryTransport::new())));
+        let keepalive = Keepalive::start(aimc.clone(), Duration::from_millis(20)).unwrap();
+
+        // Wait for a few heartbeats, however slowly the thread is scheduled
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while aimc.lock().unwrap().transport().writes().len() < 6 {
+            assert!(Instant::now() < deadline, "Heartbeats stopped");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(keepalive.take_error().is_none());
+        drop(keepalive);
+
+        let writes = aimc.lock().unwrap().transport_mut().take_writes();
+        assert_eq!(
+            writes[0],
+            AIMCMessage::SetWatchdogTimeout(20).into_bytes().to_vec()
+        );
+        let heartbeat = AIMCMessage::Heartbeat.into_bytes().to_vec();
+        assert!(writes[1..].iter().all(|w| *w == heartbeat));
+
+        // Stopped for good once dropped, as the thread has been joined
+        thread::sleep(Duration::from_millis(20));
+        assert!(aimc.lock().unwrap().transport().writes().is_empty());
+    }
+}
@@ -1,4 +1,39 @@
-mod aimc_protocol;
 mod aimc;
+#[cfg(any(feature = "tokio_support", test))]
+mod async_aimc;
+mod autotune;
+mod bootloader;
+mod bus;
+mod capture;
+mod clock;
+mod eeprom;
+mod error;
+mod homing;
+mod intel_hex;
+mod keepalive;
+mod lock;
+mod serial;
+mod shared_bus;
+mod simulation;
+mod step_response;
+mod transport;
 pub use aimc::*;
 pub use aimc_protocol::*;
+#[cfg(any(feature = "tokio_support", test))]
+pub use async_aimc::*;
+pub use autotune::*;
+pub use bootloader::*;
+pub use bus::*;
+pub use capture::*;
+pub use clock::*;
+pub use eeprom::*;
+pub use error::*;
+pub use homing::*;
+pub use intel_hex::*;
+pub use keepalive::*;
+pub use lock::*;
+pub use serial::*;
+pub use shared_bus::*;
+pub use simulation::*;
+pub use step_response::*;
+pub use transport::*;
@@ -0,0 +1,144 @@
+// Advisory locks keeping other processes off a bus we are using
+use crate::transport::nix_to_io;
+use crate::Error;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::{access, AccessFlags};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the directory for bus lock files, overriding the default
+pub const LOCK_DIR_VAR: &str = "AIMC_LOCK_DIR";
+
+/// Usual directory for bus lock files, where every process on the machine looks
+pub const LOCK_DIR: &str = "/run/lock";
+
+/// Directory holding bus lock files: `$AIMC_LOCK_DIR` if set, else `/run/lock` if we may write
+/// to it. Where `/run/lock` is kept for root, `$XDG_RUNTIME_DIR` is used, or the temp dir without
+/// it; those only keep the user's own processes off the bus.
+pub fn lock_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os(LOCK_DIR_VAR) {
+        return PathBuf::from(dir);
+    }
+    if access(LOCK_DIR, AccessFlags::W_OK).is_ok() {
+        return PathBuf::from(LOCK_DIR);
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Lock file guarding the bus at the path, e.g. `/run/lock/aimc-i2c-1.lock` for `/dev/i2c-1`
+pub fn lock_path<P: AsRef<Path>>(bus: P) -> PathBuf {
+    lock_path_in(&lock_dir(), bus)
+}
+
+fn lock_path_in<P: AsRef<Path>>(dir: &Path, bus: P) -> PathBuf {
+    let name = bus
+        .as_ref()
+        .to_string_lossy()
+        .trim_start_matches("/dev/")
+        .replace('/', "-");
+    dir.join(format!("aimc-{}.lock", name))
+}
+
+/// Exclusive advisory lock on a bus, held until dropped. The lock file records the holder's pid
+/// so that anyone else trying to take the bus can be told who has it.
+#[derive(Debug)]
+pub struct BusLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl BusLock {
+    /// Take the lock for the bus at the path, failing with `Error::BusInUse` if another process
+    /// (or another handle in this one) already holds it.
+    pub fn acquire<P: AsRef<Path>>(bus: P) -> Result<Self, Error> {
+        Self::acquire_in(&lock_dir(), bus)
+    }
+
+    /// Take the lock for the bus, keeping its lock file in the directory
+    pub fn acquire_in<P: AsRef<Path>>(dir: &Path, bus: P) -> Result<Self, Error> {
+        let path = lock_path_in(dir, &bus);
+        let open_error = |source: io::Error| Error::Open {
+            path: path.clone(),
+            source,
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(open_error)?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => (),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                let mut contents = String::new();
+                // The holder may not have written its pid yet
+                let pid = file
+                    .read_to_string(&mut contents)
+                    .ok()
+                    .and_then(|_| contents.trim().parse().ok());
+                return Err(Error::BusInUse {
+                    path: bus.as_ref().to_path_buf(),
+                    pid,
+                });
+            }
+            Err(e) => return Err(open_error(nix_to_io(e))),
+        }
+
+        let record = |file: &mut File| -> io::Result<()> {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{}", std::process::id())
+        };
+        record(&mut file).map_err(open_error)?;
+        Ok(Self { file, path })
+    }
+
+    /// Path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for BusLock {
+    fn drop(&mut self) {
+        // Leave the file in place; removing it would race with the next process to lock it
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let dir = std::env::temp_dir();
+        let bus = format!("/dev/test-lock-{}", std::process::id());
+        let lock = BusLock::acquire_in(&dir, &bus).unwrap();
+        assert!(lock.path().starts_with(&dir));
+        assert_eq!(
+            std::fs::read_to_string(lock.path()).unwrap(),
+            std::process::id().to_string()
+        );
+
+        match BusLock::acquire_in(&dir, &bus) {
+            Err(Error::BusInUse { path, pid }) => {
+                assert_eq!(path, PathBuf::from(&bus));
+                assert_eq!(pid, Some(std::process::id()));
+            }
+            other => panic!("Expected the bus to be in use, got {:?}", other),
+        }
+
+        let path = lock.path().to_path_buf();
+        drop(lock);
+        drop(BusLock::acquire_in(&dir, &bus).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
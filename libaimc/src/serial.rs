@@ -0,0 +1,424 @@
+// Serial (UART) link to a single AIMC, framed for a byte stream
+use crate::transport::nix_to_io;
+use crate::{crc8, Error, Transport};
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{self, BaudRate, FlushArg, SetArg};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+/// First byte of every frame on a serial link
+pub const SERIAL_SYNC: u8 = 0xA5;
+
+/// Longest payload a frame may carry. A length byte beyond this marks a false sync, so the
+/// decoder can resync without waiting for a frame that will never arrive.
+pub const SERIAL_MAX_PAYLOAD: usize = 64;
+
+/// How long a read waits for a complete frame unless configured otherwise
+pub const DEFAULT_SERIAL_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Frame a payload for a serial link: sync byte, payload length, payload, then the CRC-8 of the
+/// length and payload.
+pub fn serial_frame(payload: &[u8]) -> Result<Vec<u8>, SerialError> {
+    if payload.len() > SERIAL_MAX_PAYLOAD {
+        return Err(SerialError::PayloadTooLong(payload.len()));
+    }
+    let mut frame = Vec::with_capacity(payload.len() + 3);
+    frame.push(SERIAL_SYNC);
+    frame.push(payload.len() as u8);
+    frame.extend_from_slice(payload);
+    frame.push(crc8(&frame[1..]));
+    Ok(frame)
+}
+
+/// Reassembles frames from a byte stream, skipping line noise and corrupted frames.
+#[derive(Debug, Default, Clone)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append bytes received from the stream
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Forget everything received so far
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Take the payload of the next complete, intact frame, if one has been received
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.next_frame_within(SERIAL_MAX_PAYLOAD)
+    }
+
+    /// Take the next frame with a payload of at most `max_payload` bytes. Knowing how long the
+    /// awaited reply is lets a noise byte that looks like a sync be passed over straight away,
+    /// rather than after waiting for a frame of whatever length the next byte claims.
+    pub fn next_frame_within(&mut self, max_payload: usize) -> Option<Vec<u8>> {
+        let max_payload = max_payload.min(SERIAL_MAX_PAYLOAD);
+        loop {
+            match self.buffer.iter().position(|b| *b == SERIAL_SYNC) {
+                Some(start) => drop(self.buffer.drain(..start)),
+                None => {
+                    self.buffer.clear();
+                    return None;
+                }
+            }
+            if self.buffer.len() < 2 {
+                return None;
+            }
+            let len = self.buffer[1] as usize;
+            let end = len + 2;
+            if len <= max_payload {
+                if self.buffer.len() <= end {
+                    return None;
+                }
+                if crc8(&self.buffer[1..end]) == self.buffer[end] {
+                    let frame = self.buffer[2..end].to_vec();
+                    self.buffer.drain(..=end);
+                    return Some(frame);
+                }
+            }
+            // Not a frame after all; look for the next sync byte
+            self.buffer.remove(0);
+        }
+    }
+}
+
+fn baud_rate(baud: u32) -> Option<BaudRate> {
+    Some(match baud {
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        460800 => BaudRate::B460800,
+        500000 => BaudRate::B500000,
+        921600 => BaudRate::B921600,
+        1000000 => BaudRate::B1000000,
+        _ => return None,
+    })
+}
+
+/// Serial port (`/dev/ttyUSB*`, `/dev/ttyACM*`, ...) with a single AIMC on the other end.
+pub struct SerialTransport {
+    port: File,
+    path: PathBuf,
+    baud: u32,
+    timeout: Duration,
+    decoder: FrameDecoder,
+    /// Whether a request has been written since the last read, so its reply may be on the way
+    awaiting_reply: bool,
+}
+
+impl SerialTransport {
+    /// Open the specified serial port in raw mode at the baud rate.
+    pub fn open<P: AsRef<Path>>(serial_port: P, baud: u32) -> Result<Self, Error> {
+        let path = serial_port.as_ref();
+        let open_error = |source: io::Error| Error::Open {
+            path: path.to_path_buf(),
+            source,
+        };
+        let rate = baud_rate(baud).ok_or_else(|| {
+            open_error(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                SerialError::UnsupportedBaud(baud),
+            ))
+        })?;
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(OFlag::O_NOCTTY.bits())
+            .open(path)
+            .map_err(open_error)?;
+
+        let fd = port.as_raw_fd();
+        let configure = || -> nix::Result<()> {
+            let mut settings = termios::tcgetattr(fd)?;
+            termios::cfmakeraw(&mut settings);
+            termios::cfsetspeed(&mut settings, rate)?;
+            termios::tcsetattr(fd, SetArg::TCSANOW, &settings)?;
+            // Whatever arrived before we were listening is of no use
+            termios::tcflush(fd, FlushArg::TCIOFLUSH)
+        };
+        configure().map_err(|e| open_error(nix_to_io(e)))?;
+
+        Ok(Self {
+            port,
+            path: path.to_path_buf(),
+            baud,
+            timeout: DEFAULT_SERIAL_TIMEOUT,
+            decoder: FrameDecoder::new(),
+            awaiting_reply: false,
+        })
+    }
+
+    /// Wait at most this long for each reply
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Path of the serial port
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Baud rate the port was opened at
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Drop everything received so far, so that a reply which arrived after its read timed out
+    /// is not taken as the answer to the next request
+    fn discard_input(&mut self) -> Result<(), Error> {
+        self.decoder.clear();
+        termios::tcflush(self.port.as_raw_fd(), FlushArg::TCIFLUSH)
+            .map_err(|e| Error::Bus(nix_to_io(e)))
+    }
+
+    /// Block until more bytes arrive or the deadline passes
+    fn receive(&mut self, deadline: Instant) -> Result<(), Error> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(self.port.as_raw_fd(), PollFlags::POLLIN)];
+        let ready =
+            poll(&mut fds, remaining.as_millis() as i32).map_err(|e| Error::Bus(nix_to_io(e)))?;
+        if ready == 0 {
+            return Err(Error::Timeout);
+        }
+        let mut chunk = [0u8; 64];
+        let read = self.port.read(&mut chunk).map_err(Error::Bus)?;
+        self.decoder.extend(&chunk[..read]);
+        Ok(())
+    }
+}
+
+impl Transport for SerialTransport {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let frame = serial_frame(data).map_err(|e| Error::Transport(Box::new(e)))?;
+        self.discard_input()?;
+        self.awaiting_reply = true;
+        self.port.write_all(&frame).map_err(Error::Bus)
+    }
+
+    /// Read a reply to the last request written, or a fresh frame if a read has already been
+    /// made since then
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        if !std::mem::replace(&mut self.awaiting_reply, false) {
+            self.discard_input()?;
+        }
+        let deadline = Instant::now() + self.timeout;
+        let frame = loop {
+            if let Some(frame) = self.decoder.next_frame_within(buffer.len()) {
+                break frame;
+            }
+            self.receive(deadline)?;
+        };
+        if frame.len() != buffer.len() {
+            return Err(Error::Transport(Box::new(SerialError::LengthMismatch {
+                expected: buffer.len(),
+                actual: frame.len(),
+            })));
+        }
+        buffer.copy_from_slice(&frame);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SerialError {
+    /// The baud rate is not one the port can be configured for
+    UnsupportedBaud(u32),
+    /// The payload does not fit in a single frame
+    PayloadTooLong(usize),
+    /// A frame arrived intact, but not of the length requested
+    LengthMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialError::UnsupportedBaud(baud) => write!(f, "Unsupported baud rate {}", baud),
+            SerialError::PayloadTooLong(len) => {
+                write!(f, "Payload of {} bytes is too long for a frame", len)
+            }
+            SerialError::LengthMismatch { expected, actual } => write!(
+                f,
+                "Frame of {} bytes received, expected {} bytes",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for SerialError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parameter_reply_bytes, AIMCMessage, Parameter, ParameterValue, Status, StatusFlags, AIMC,
+    };
+    use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+
+    /// Open a pseudo-terminal pair, returning the master and a transport on the slave
+    fn pty() -> (PtyMaster, SerialTransport) {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let path = ptsname_r(&master).unwrap();
+        let transport = SerialTransport::open(path, 115200)
+            .unwrap()
+            .with_timeout(Duration::from_millis(50));
+        (master, transport)
+    }
+
+    fn read_frame(master: &PtyMaster) -> Vec<u8> {
+        let mut decoder = FrameDecoder::new();
+        loop {
+            if let Some(frame) = decoder.next_frame() {
+                return frame;
+            }
+            let mut chunk = [0u8; 64];
+            let read = nix::unistd::read(master.as_raw_fd(), &mut chunk).unwrap();
+            decoder.extend(&chunk[..read]);
+        }
+    }
+
+    #[test]
+    fn test_frame_decoder() {
+        let frame = serial_frame(&[1, SERIAL_SYNC, 3]).unwrap();
+        let mut corrupted = frame.clone();
+        corrupted[3] ^= 0x10;
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&[0x00, SERIAL_SYNC]);
+        decoder.extend(&corrupted);
+        decoder.extend(&frame[..3]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.extend(&frame[3..]);
+        assert_eq!(decoder.next_frame(), Some(vec![1, SERIAL_SYNC, 3]));
+        assert_eq!(decoder.next_frame(), None);
+
+        // Noise that looks like the start of a long frame holds up a reply of known length only
+        // until the reply's own frame is buffered
+        let reply = serial_frame(&[7; 16]).unwrap();
+        decoder.extend(&[SERIAL_SYNC, 60]);
+        decoder.extend(&reply);
+        assert_eq!(decoder.clone().next_frame(), None);
+        assert_eq!(decoder.next_frame_within(16), Some(vec![7; 16]));
+
+        assert_eq!(serial_frame(&[0; 65]), Err(SerialError::PayloadTooLong(65)));
+    }
+
+    #[test]
+    fn test_pty() {
+        let (master, transport) = pty();
+        let mut aimc = AIMC::from_transport(transport);
+
+        aimc.write_message(AIMCMessage::SetTarget(1.5)).unwrap();
+        assert_eq!(
+            read_frame(&master),
+            AIMCMessage::SetTarget(1.5).into_bytes().to_vec()
+        );
+
+        let status = Status {
+            encoder: 4.0,
+            target: 1.5,
+            pid_out: -2.0,
+            flags: StatusFlags::empty(),
+        };
+        let mut stream = vec![0x13, 0x37, SERIAL_SYNC, 60];
+        let mut corrupted = serial_frame(&status.into_bytes()).unwrap();
+        corrupted[5] ^= 0x01;
+        stream.extend(corrupted);
+        stream.extend(serial_frame(&status.into_bytes()).unwrap());
+        nix::unistd::write(master.as_raw_fd(), &stream).unwrap();
+        let read = aimc.status().unwrap();
+        assert_eq!(read.encoder, 4.0);
+        assert_eq!(read.pid_out, -2.0);
+
+        assert!(matches!(aimc.status(), Err(Error::Timeout)));
+
+        aimc.write_message(AIMCMessage::Reset).unwrap();
+        nix::unistd::write(master.as_raw_fd(), &serial_frame(&[1, 2]).unwrap()).unwrap();
+        let mut buffer = [0u8; 16];
+        assert!(matches!(
+            aimc.transport_mut().read(&mut buffer),
+            Err(Error::Transport(_))
+        ));
+    }
+
+    /// Wait until the transport has input waiting, so a test can be sure it has arrived
+    fn wait_readable(transport: &SerialTransport) {
+        let mut fds = [PollFd::new(transport.port.as_raw_fd(), PollFlags::POLLIN)];
+        assert_eq!(poll(&mut fds, 1000).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_late_reply() {
+        let (master, transport) = pty();
+        let mut aimc = AIMC::from_transport(transport);
+        let status = Status {
+            encoder: 1.0,
+            target: 0.0,
+            pid_out: 0.0,
+            flags: StatusFlags::empty(),
+        };
+
+        // Half a status arrives in time, the rest only after the read has given up
+        aimc.write_message(AIMCMessage::SetTarget(0.0)).unwrap();
+        let late = serial_frame(&status.into_bytes()).unwrap();
+        nix::unistd::write(master.as_raw_fd(), &late[..8]).unwrap();
+        assert!(matches!(aimc.status(), Err(Error::Timeout)));
+        nix::unistd::write(master.as_raw_fd(), &late[8..]).unwrap();
+        wait_readable(aimc.transport());
+        assert!(matches!(aimc.status(), Err(Error::Timeout)));
+
+        // A reply to a request that timed out is not taken for the reply to the next
+        let reply = |kp| {
+            let reply = parameter_reply_bytes(Parameter::Kp, ParameterValue::Float(kp));
+            serial_frame(&reply).unwrap()
+        };
+        assert!(matches!(
+            aimc.read_parameter(Parameter::Kp),
+            Err(Error::Timeout)
+        ));
+        read_frame(&master);
+        nix::unistd::write(master.as_raw_fd(), &reply(1.0)).unwrap();
+        wait_readable(aimc.transport());
+        let responder = std::thread::spawn(move || {
+            read_frame(&master);
+            nix::unistd::write(master.as_raw_fd(), &reply(2.0)).unwrap();
+            master
+        });
+        assert_eq!(
+            aimc.read_parameter(Parameter::Kp).unwrap(),
+            ParameterValue::Float(2.0)
+        );
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_baud() {
+        assert!(matches!(
+            SerialTransport::open("/dev/null", 12345),
+            Err(Error::Open { .. })
+        ));
+    }
+}
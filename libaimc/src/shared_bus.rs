@@ -0,0 +1,237 @@
+// Sharing one bus between devices and threads
+use crate::{Bus, BusDevice, Error, I2CBus};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Order in which waiting transactions get the bus
+struct Turns {
+    next_ticket: u64,
+    serving: u64,
+}
+
+struct Shared<B> {
+    turns: Mutex<Turns>,
+    turn_changed: Condvar,
+    bus: Mutex<B>,
+}
+
+/// A bus shared between any number of handles and threads. Each transaction has the bus to
+/// itself, and waiting transactions are served in the order they arrived.
+pub struct SharedBus<B> {
+    shared: Arc<Shared<B>>,
+}
+
+impl<B> Clone for SharedBus<B> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Hands the bus to the next ticket, even if the transaction panicked
+struct Turn<'a> {
+    turns: &'a Mutex<Turns>,
+    turn_changed: &'a Condvar,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        lock(self.turns).serving += 1;
+        self.turn_changed.notify_all();
+    }
+}
+
+/// A panic elsewhere leaves nothing half-updated that we care about
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<B: Bus> SharedBus<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                turns: Mutex::new(Turns {
+                    next_ticket: 0,
+                    serving: 0,
+                }),
+                turn_changed: Condvar::new(),
+                bus: Mutex::new(bus),
+            }),
+        }
+    }
+
+    /// A handle to the device at the address, usable wherever a `Transport` is expected
+    pub fn device(&self, address: u16) -> BusDevice<Self> {
+        BusDevice::new(self.clone(), address)
+    }
+
+    /// Wait for our turn, then run a transaction with the bus to ourselves
+    fn transaction<R>(&self, transaction: impl FnOnce(&mut B) -> R) -> R {
+        let shared = &*self.shared;
+        let mut turns = lock(&shared.turns);
+        let ticket = turns.next_ticket;
+        turns.next_ticket += 1;
+        while turns.serving != ticket {
+            turns = shared
+                .turn_changed
+                .wait(turns)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(turns);
+
+        let _turn = Turn {
+            turns: &shared.turns,
+            turn_changed: &shared.turn_changed,
+        };
+        transaction(&mut lock(&shared.bus))
+    }
+}
+
+impl<B: Bus> Bus for SharedBus<B> {
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+        self.transaction(|bus| bus.write(address, data))
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(|bus| bus.read(address, buffer))
+    }
+}
+
+type Opener<B> = Box<dyn Fn(&Path) -> Result<B, Error> + Send + Sync>;
+
+/// Owns a single handle to each bus in use, opening it the first time a device on it is asked
+/// for. Every device on a bus shares that handle, and with it the bus's lock.
+pub struct BusManager<B = I2CBus> {
+    open: Opener<B>,
+    buses: Mutex<HashMap<PathBuf, SharedBus<B>>>,
+}
+
+impl BusManager<I2CBus> {
+    /// Manage Linux I2C buses
+    pub fn new() -> Self {
+        Self::with_opener(|path| I2CBus::open(path))
+    }
+}
+
+impl Default for BusManager<I2CBus> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: Bus> BusManager<B> {
+    /// Manage buses opened by the given function
+    pub fn with_opener<F>(open: F) -> Self
+    where
+        F: Fn(&Path) -> Result<B, Error> + Send + Sync + 'static,
+    {
+        Self {
+            open: Box::new(open),
+            buses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The bus at the path, opening it if this is the first use
+    pub fn bus<P: AsRef<Path>>(&self, path: P) -> Result<SharedBus<B>, Error> {
+        let path = path.as_ref();
+        let mut buses = lock(&self.buses);
+        if let Some(bus) = buses.get(path) {
+            return Ok(bus.clone());
+        }
+        let bus = SharedBus::new((self.open)(path)?);
+        buses.insert(path.to_path_buf(), bus.clone());
+        Ok(bus)
+    }
+
+    /// The device at the address on the bus at the path
+    pub fn device<P: AsRef<Path>>(
+        &self,
+        path: P,
+        address: u16,
+    ) -> Result<BusDevice<SharedBus<B>>, Error> {
+        Ok(self.bus(path)?.device(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AIMCMessage, MemoryBus, Transport, AIMC};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Counts writes, and checks no other transaction is under way during one
+    struct Exclusive {
+        writes: Arc<AtomicUsize>,
+        busy: Arc<AtomicBool>,
+    }
+
+    impl Transport for Exclusive {
+        fn write(&mut self, _data: &[u8]) -> Result<(), Error> {
+            assert!(!self.busy.swap(true, Ordering::SeqCst));
+            thread::yield_now();
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.busy.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn read(&mut self, _buffer: &mut [u8]) -> Result<(), Error> {
+            Err(Error::Transport("write-only test transport".into()))
+        }
+    }
+
+    #[test]
+    fn test_shared_bus() {
+        let busy = Arc::new(AtomicBool::new(false));
+        let counters: Vec<_> = (0..4).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+        let mut memory = MemoryBus::new();
+        for (address, writes) in counters.iter().enumerate() {
+            memory.attach(
+                address as u16,
+                Exclusive {
+                    writes: writes.clone(),
+                    busy: busy.clone(),
+                },
+            );
+        }
+        let bus = SharedBus::new(memory);
+
+        let threads: Vec<_> = (0..4)
+            .map(|address| {
+                let mut aimc = AIMC::from_transport(bus.device(address));
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        aimc.write_message(AIMCMessage::Reset).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        for writes in &counters {
+            assert_eq!(writes.load(Ordering::SeqCst), 100);
+        }
+    }
+
+    #[test]
+    fn test_bus_manager() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let count = opened.clone();
+        let manager = BusManager::with_opener(move |path| {
+            count.fetch_add(1, Ordering::SeqCst);
+            if path == Path::new("/dev/missing") {
+                return Err(Error::NoDevice { address: 0 });
+            }
+            Ok(MemoryBus::new())
+        });
+
+        manager.device("/dev/i2c-1", 0x10).unwrap();
+        manager.device("/dev/i2c-1", 0x11).unwrap();
+        manager.device("/dev/i2c-2", 0x10).unwrap();
+        assert!(manager.device("/dev/missing", 0x10).is_err());
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+    }
+}
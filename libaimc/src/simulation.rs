@@ -0,0 +1,550 @@
+// Software stand-in for an AIMC and the motor it drives
+use crate::{
+    framed_parameter_reply_bytes, parameter_reply_bytes, AIMCMessage, Clock, ControlMode,
+    DecodeError, DeviceConfig, Error, Parameter, Status, StatusFlags, SystemClock, Transport,
+    FRAMED_MESSAGE_LEN, FRAMED_PARAMETER_REPLY_LEN, FRAMED_STATUS_LEN, PARAMETER_REPLY_LEN,
+};
+#[cfg(feature = "serde_support")]
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Integration step of the plant and control loop
+const SIMULATION_STEP: Duration = Duration::from_millis(1);
+
+/// Physical parameters of a simulated motor and the mechanism it moves.
+///
+/// The motor is modelled as `inertia * dv/dt = gain * pwm - damping * v`, with hard stops at
+/// each end of travel. The limit switch sits at `travel_min`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde_support", serde(default))]
+pub struct PlantModel {
+    pub inertia: f32,
+    pub damping: f32,
+    pub gain: f32,
+    pub travel_min: f32,
+    pub travel_max: f32,
+}
+
+impl Default for PlantModel {
+    fn default() -> Self {
+        Self {
+            inertia: 0.01,
+            damping: 0.1,
+            gain: 1.0,
+            travel_min: -10_000.0,
+            travel_max: 10_000.0,
+        }
+    }
+}
+
+/// Firmware-side state, mirroring what an AIMC keeps in RAM.
+#[derive(Debug, Clone, Default)]
+struct Firmware {
+    config: DeviceConfig,
+    target: f32,
+    homing_speed: i32,
+    integral: f32,
+    last_error: Option<f32>,
+    output: f32,
+    pending_read: Option<Parameter>,
+    /// Zero while the watchdog is off
+    watchdog_timeout: Duration,
+    /// When the host was last heard from
+    last_heard: Duration,
+    /// Set when the watchdog expires, until re-enabled
+    fault: bool,
+}
+
+/// Simulated AIMC. Speaks the device side of the wire protocol over the `Transport` trait and
+/// runs its control loop against a `PlantModel`, advancing with the supplied clock.
+pub struct SimulatedAIMC<C = SystemClock> {
+    plant: PlantModel,
+    clock: C,
+    simulated_until: Duration,
+    firmware: Firmware,
+    /// Parameters saved by `SaveConfig`, which outlive the firmware's RAM
+    eeprom: DeviceConfig,
+    position: f32,
+    velocity: f32,
+    encoder_offset: f32,
+    sequence: u8,
+}
+
+impl SimulatedAIMC<SystemClock> {
+    /// Create a simulated AIMC running in real time.
+    pub fn new(plant: PlantModel) -> Self {
+        Self::with_clock(plant, SystemClock::new())
+    }
+}
+
+impl<C: Clock> SimulatedAIMC<C> {
+    /// Create a simulated AIMC driven by the specified clock.
+    pub fn with_clock(plant: PlantModel, clock: C) -> Self {
+        let simulated_until = clock.now();
+        Self {
+            plant,
+            clock,
+            simulated_until,
+            firmware: Default::default(),
+            eeprom: Default::default(),
+            position: 0.0,
+            velocity: 0.0,
+            encoder_offset: 0.0,
+            sequence: 0,
+        }
+    }
+
+    /// True position of the mechanism, independent of encoder zeroing and polarity
+    pub fn position(&self) -> f32 {
+        self.position
+    }
+
+    /// Whether the limit switch is currently pressed
+    pub fn limit_switch(&self) -> bool {
+        self.position <= self.plant.travel_min
+    }
+
+    /// Status as the firmware would currently report it
+    pub fn status(&self) -> Status {
+        Status {
+            encoder: self.encoder(),
+            target: self.firmware.target,
+            pid_out: self.firmware.output,
+            flags: self.flags(),
+        }
+    }
+
+    fn flags(&self) -> StatusFlags {
+        let firmware = &self.firmware;
+        let mut flags = StatusFlags::empty().with_mode(firmware.config.mode);
+        for (flag, set) in [
+            (StatusFlags::LIMIT_MIN, self.limit_switch()),
+            (StatusFlags::ENABLED, firmware.config.enabled),
+            (StatusFlags::HOMING, firmware.homing_speed != 0),
+            (StatusFlags::FAULT, firmware.fault),
+        ]
+        .iter()
+        {
+            if *set {
+                flags.insert(*flag);
+            }
+        }
+        flags
+    }
+
+    /// Parameters as the firmware currently holds them
+    pub fn config(&self) -> DeviceConfig {
+        self.firmware.config
+    }
+
+    /// Parameters as saved in EEPROM
+    pub fn saved_config(&self) -> DeviceConfig {
+        self.eeprom
+    }
+
+    /// Restart the firmware as if power had been removed and restored. Everything held in RAM
+    /// is lost and the saved parameters are loaded, though the mechanism stays where it was.
+    pub fn power_cycle(&mut self) {
+        self.update();
+        self.firmware = Firmware {
+            config: DeviceConfig {
+                enabled: false,
+                ..self.eeprom
+            },
+            last_heard: self.simulated_until,
+            ..Default::default()
+        };
+        self.encoder_offset = self.position;
+        self.velocity = 0.0;
+    }
+
+    /// Apply a message as the firmware would on receipt
+    pub fn handle_message(&mut self, message: AIMCMessage) {
+        let firmware = &mut self.firmware;
+        firmware.config.apply(message);
+        firmware.last_heard = self.simulated_until;
+        match message {
+            AIMCMessage::Enable(enable) => {
+                firmware.fault &= !enable;
+                firmware.integral = 0.0;
+                firmware.last_error = None;
+            }
+            AIMCMessage::SetTarget(target) => firmware.target = target,
+            AIMCMessage::Reset => {
+                firmware.integral = 0.0;
+                firmware.last_error = None;
+                self.encoder_offset = self.position;
+            }
+            AIMCMessage::Home(speed) => firmware.homing_speed = speed,
+            AIMCMessage::ReadParameter(parameter) => firmware.pending_read = Some(parameter),
+            AIMCMessage::SetWatchdogTimeout(ms) => {
+                firmware.watchdog_timeout = Duration::from_millis(u64::from(ms))
+            }
+            AIMCMessage::SaveConfig => self.eeprom = firmware.config,
+            AIMCMessage::LoadConfig => {
+                firmware.config = DeviceConfig {
+                    enabled: firmware.config.enabled,
+                    ..self.eeprom
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Run the simulation forward to the clock's current time
+    pub fn update(&mut self) {
+        let now = self.clock.now();
+        while self.simulated_until + SIMULATION_STEP <= now {
+            self.step(SIMULATION_STEP.as_secs_f32());
+            self.simulated_until += SIMULATION_STEP;
+            self.check_watchdog();
+        }
+    }
+
+    /// Stop driving if the host has gone quiet for longer than the watchdog allows
+    fn check_watchdog(&mut self) {
+        let firmware = &mut self.firmware;
+        if firmware.watchdog_timeout > Duration::from_secs(0)
+            && self.simulated_until - firmware.last_heard > firmware.watchdog_timeout
+        {
+            firmware.fault |= firmware.config.enabled || firmware.homing_speed != 0;
+            firmware.config.enabled = false;
+            firmware.homing_speed = 0;
+        }
+    }
+
+    fn encoder(&self) -> f32 {
+        let encoder = self.position - self.encoder_offset;
+        if self.firmware.config.encoder_polarity {
+            -encoder
+        } else {
+            encoder
+        }
+    }
+
+    /// Target after the firmware's soft limits. Limits are ignored until max exceeds min.
+    fn limited_target(&self) -> f32 {
+        let config = &self.firmware.config;
+        if config.limit_target_max > config.limit_target_min {
+            self.firmware
+                .target
+                .max(config.limit_target_min)
+                .min(config.limit_target_max)
+        } else {
+            self.firmware.target
+        }
+    }
+
+    fn control(&mut self, dt: f32) -> f32 {
+        let limit = f32::from(self.firmware.config.limit_pwm);
+        if self.firmware.homing_speed != 0 {
+            return (self.firmware.homing_speed as f32).max(-limit).min(limit);
+        }
+        if !self.firmware.config.enabled {
+            return 0.0;
+        }
+        let error = self.limited_target() - self.encoder();
+        let firmware = &mut self.firmware;
+        let DeviceConfig { kp, ki, kd, .. } = firmware.config;
+        let output = match firmware.config.mode {
+            ControlMode::PWM => firmware.target,
+            ControlMode::Pneumatic => limit * error.signum(),
+            ControlMode::PID => {
+                let derivative = firmware.last_error.map_or(0.0, |last| (error - last) / dt);
+                firmware.last_error = Some(error);
+                let unsaturated =
+                    kp * error + ki * (firmware.integral + error * dt) + kd * derivative;
+                // Conditional integration keeps the integrator from winding up at the limit
+                if unsaturated.abs() < limit || unsaturated.signum() != error.signum() {
+                    firmware.integral += error * dt;
+                }
+                kp * error + ki * firmware.integral + kd * derivative
+            }
+        };
+        output.max(-limit).min(limit)
+    }
+
+    fn step(&mut self, dt: f32) {
+        let output = self.control(dt);
+        self.firmware.output = output;
+
+        let plant = self.plant;
+        let acceleration = (plant.gain * output - plant.damping * self.velocity) / plant.inertia;
+        self.velocity += acceleration * dt;
+        self.position += self.velocity * dt;
+
+        if self.position <= plant.travel_min {
+            self.position = plant.travel_min;
+            self.velocity = self.velocity.max(0.0);
+        } else if self.position >= plant.travel_max {
+            self.position = plant.travel_max;
+            self.velocity = self.velocity.min(0.0);
+        }
+
+        // Homing zeroes the encoder at the limit switch
+        if self.firmware.homing_speed != 0 && self.limit_switch() {
+            self.encoder_offset = self.position;
+        }
+    }
+}
+
+// The wire format is told apart by transfer length, so one simulated device speaks both
+impl<C: Clock> Transport for SimulatedAIMC<C> {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.update();
+        match data.len() {
+            5 => {
+                let mut bytes = [0u8; 5];
+                bytes.copy_from_slice(data);
+                self.handle_message(AIMCMessage::from_bytes(bytes)?);
+            }
+            FRAMED_MESSAGE_LEN => {
+                let mut bytes = [0u8; FRAMED_MESSAGE_LEN];
+                bytes.copy_from_slice(data);
+                match AIMCMessage::from_framed_bytes(bytes) {
+                    // Firmware drops corrupted frames, leaving the host to notice the stale status
+                    Err(DecodeError::Checksum { .. }) => (),
+                    Err(e) => return Err(e.into()),
+                    Ok((sequence, message)) => {
+                        self.handle_message(message);
+                        self.sequence = sequence;
+                    }
+                }
+            }
+            actual => {
+                return Err(Error::ShortWrite {
+                    expected: if actual < 5 { 5 } else { FRAMED_MESSAGE_LEN },
+                    actual,
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.update();
+        let status = self.status();
+        // A parameter request is answered by the next read, after which status is served again
+        let pending = self.firmware.pending_read.take();
+        let config = self.firmware.config;
+        match (buffer.len(), pending) {
+            (PARAMETER_REPLY_LEN, Some(parameter)) => {
+                buffer.copy_from_slice(&parameter_reply_bytes(parameter, config.get(parameter)))
+            }
+            (FRAMED_PARAMETER_REPLY_LEN, Some(parameter)) => buffer.copy_from_slice(
+                &framed_parameter_reply_bytes(parameter, config.get(parameter), self.sequence),
+            ),
+            (16, _) => buffer.copy_from_slice(&status.into_bytes()),
+            (FRAMED_STATUS_LEN, _) => {
+                buffer.copy_from_slice(&status.into_framed_bytes(self.sequence))
+            }
+            (expected, _) => {
+                return Err(Error::ShortRead {
+                    expected,
+                    actual: if expected < 16 { 16 } else { FRAMED_STATUS_LEN },
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, ParameterValue, ProtocolVersion, AIMC};
+
+    fn simulated() -> (AIMC<SimulatedAIMC<ManualClock>>, ManualClock) {
+        let clock = ManualClock::new();
+        let device = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        (AIMC::from_transport(device), clock)
+    }
+
+    fn run(aimc: &mut AIMC<SimulatedAIMC<ManualClock>>, clock: &ManualClock, secs: f32) -> Status {
+        clock.advance(Duration::from_secs_f32(secs));
+        aimc.status().unwrap()
+    }
+
+    #[test]
+    fn test_disabled_holds_still() {
+        let (mut aimc, clock) = simulated();
+        aimc.write_message(AIMCMessage::SetKp(1.0)).unwrap();
+        aimc.write_message(AIMCMessage::SetTarget(100.0)).unwrap();
+        let status = run(&mut aimc, &clock, 1.0);
+        assert_eq!(status.encoder, 0.0);
+        assert_eq!(status.pid_out, 0.0);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let (mut aimc, clock) = simulated();
+        aimc.write_message(AIMCMessage::ModePWM).unwrap();
+        aimc.write_message(AIMCMessage::SetTarget(10.0)).unwrap();
+        aimc.write_message(AIMCMessage::Enable(true)).unwrap();
+        aimc.write_message(AIMCMessage::SetWatchdogTimeout(100))
+            .unwrap();
+
+        // Heartbeats keep it going
+        for _ in 0..10 {
+            clock.advance(Duration::from_millis(50));
+            aimc.write_message(AIMCMessage::Heartbeat).unwrap();
+        }
+        assert!(aimc.transport().config().enabled);
+        assert_eq!(aimc.status().unwrap().pid_out, 10.0);
+
+        // Silence does not
+        let status = run(&mut aimc, &clock, 0.2);
+        assert!(!aimc.transport().config().enabled);
+        assert_eq!(status.pid_out, 0.0);
+        assert!(status.flags.contains(StatusFlags::FAULT));
+        assert!(!status.flags.contains(StatusFlags::ENABLED));
+
+        // Re-enabling clears the fault
+        aimc.write_message(AIMCMessage::Enable(true)).unwrap();
+        let flags = aimc.status().unwrap().flags;
+        assert_eq!(flags, StatusFlags::ENABLED.with_mode(ControlMode::PWM));
+    }
+
+    #[test]
+    fn test_eeprom() {
+        let (mut aimc, clock) = simulated();
+        for message in [
+            AIMCMessage::SetKp(2.0),
+            AIMCMessage::Enable(true),
+            AIMCMessage::SaveConfig,
+            AIMCMessage::SetKp(3.0),
+        ]
+        .iter()
+        {
+            aimc.write_message(*message).unwrap();
+        }
+        assert_eq!(aimc.transport().saved_config().kp, 2.0);
+
+        aimc.write_message(AIMCMessage::LoadConfig).unwrap();
+        assert_eq!(aimc.transport().config().kp, 2.0);
+        assert!(aimc.transport().config().enabled);
+
+        // Saved parameters survive a power cycle, but the device starts disabled
+        aimc.write_message(AIMCMessage::SetTarget(5.0)).unwrap();
+        clock.advance(Duration::from_millis(10));
+        aimc.transport_mut().power_cycle();
+        let status = aimc.status().unwrap();
+        assert_eq!(status.target, 0.0);
+        assert!(!status.flags.contains(StatusFlags::ENABLED));
+        assert_eq!(
+            aimc.transport().config(),
+            DeviceConfig {
+                kp: 2.0,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_pid_reaches_target() {
+        let (mut aimc, clock) = simulated();
+        for message in [
+            AIMCMessage::ModePID,
+            AIMCMessage::SetKp(2.0),
+            AIMCMessage::SetKi(0.5),
+            AIMCMessage::SetTarget(100.0),
+            AIMCMessage::Enable(true),
+        ]
+        .iter()
+        {
+            aimc.write_message(*message).unwrap();
+        }
+        let status = run(&mut aimc, &clock, 5.0);
+        assert!((status.encoder - 100.0).abs() < 1.0, "{:?}", status);
+        assert_eq!(status.target, 100.0);
+    }
+
+    #[test]
+    fn test_pwm_limit_and_polarity() {
+        let (mut aimc, clock) = simulated();
+        aimc.write_message(AIMCMessage::ModePWM).unwrap();
+        aimc.write_message(AIMCMessage::LimitPwm(10)).unwrap();
+        aimc.write_message(AIMCMessage::EncoderPolarity(true))
+            .unwrap();
+        aimc.write_message(AIMCMessage::SetTarget(50.0)).unwrap();
+        aimc.write_message(AIMCMessage::Enable(true)).unwrap();
+        let status = run(&mut aimc, &clock, 1.0);
+        assert_eq!(status.pid_out, 10.0);
+        assert!(status.encoder < 0.0);
+        assert!(aimc.transport().position() > 0.0);
+    }
+
+    #[test]
+    fn test_homing_trips_limit_switch() {
+        let (mut aimc, clock) = simulated();
+        aimc.write_message(AIMCMessage::Home(-255)).unwrap();
+        let status = run(&mut aimc, &clock, 10.0);
+        assert!(status
+            .flags
+            .contains(StatusFlags::LIMIT_MIN | StatusFlags::HOMING));
+        assert_eq!(status.encoder, 0.0);
+        aimc.write_message(AIMCMessage::Home(0)).unwrap();
+        assert_eq!(run(&mut aimc, &clock, 0.1).pid_out, 0.0);
+    }
+
+    #[test]
+    fn test_malformed_write() {
+        let (mut aimc, _) = simulated();
+        assert!(matches!(
+            aimc.transport_mut().write(&[0, 0, 0, 0, 0]),
+            Err(Error::Decode(DecodeError::UnknownOpcode(0)))
+        ));
+        assert!(matches!(
+            aimc.transport_mut().write(&[1, 0, 0, 0]),
+            Err(Error::ShortWrite {
+                expected: 5,
+                actual: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_framed_protocol() {
+        let clock = ManualClock::new();
+        let device = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        let mut aimc = AIMC::from_transport(device).with_protocol(ProtocolVersion::Framed);
+        aimc.write_message(AIMCMessage::SetTarget(5.0)).unwrap();
+        assert_eq!(aimc.status().unwrap().target, 5.0);
+
+        // A corrupted frame is dropped, so the status still echoes the previous sequence number
+        let mut frame = AIMCMessage::SetTarget(6.0).into_framed_bytes(2);
+        frame[3] ^= 0x10;
+        aimc.transport_mut().write(&frame).unwrap();
+        let mut buffer = [0u8; FRAMED_STATUS_LEN];
+        aimc.transport_mut().read(&mut buffer).unwrap();
+        let (sequence, status) = Status::from_framed_bytes(buffer).unwrap();
+        assert_eq!(sequence, 1);
+        assert_eq!(status.target, 5.0);
+    }
+
+    #[test]
+    fn test_read_config() {
+        let (aimc, _) = simulated();
+        let mut aimc = aimc.with_protocol(ProtocolVersion::Framed);
+        let messages = [
+            AIMCMessage::ModePneumatic,
+            AIMCMessage::SetKp(0.25),
+            AIMCMessage::LimitPwm(64),
+            AIMCMessage::LimitTargetMax(100.0),
+            AIMCMessage::EncoderPolarity(true),
+        ];
+        let mut expected = DeviceConfig::default();
+        for message in messages.iter() {
+            aimc.write_message(*message).unwrap();
+            expected.apply(*message);
+        }
+        assert_eq!(aimc.read_config().unwrap(), expected);
+        assert_eq!(
+            aimc.read_parameter(Parameter::LimitPwm).unwrap(),
+            ParameterValue::Pwm(64)
+        );
+        // Status is served again once the reply has been read
+        assert_eq!(aimc.status().unwrap().target, 0.0);
+    }
+}
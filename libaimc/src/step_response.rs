@@ -0,0 +1,240 @@
+// Step-response measurement, for comparing gain sets objectively
+use crate::{AIMCMessage, Clock, Error, Transport, AIMC};
+#[cfg(feature = "serde_support")]
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+
+/// Settings for a step-response measurement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepConfig {
+    /// Target to step to
+    pub target: f32,
+    pub sample_period: Duration,
+    /// How long to sample for after the step
+    pub duration: Duration,
+    /// Half-width of the settling band, as a fraction of the step size
+    pub settling_band: f32,
+}
+
+impl Default for StepConfig {
+    fn default() -> Self {
+        Self {
+            target: 0.0,
+            sample_period: Duration::from_millis(10),
+            duration: Duration::from_secs(5),
+            settling_band: 0.02,
+        }
+    }
+}
+
+/// Status sampled during a step response. Times are in seconds since the step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct StepSample {
+    pub time: f32,
+    pub encoder: f32,
+    pub target: f32,
+    pub pid_out: f32,
+}
+
+/// Figures of merit for a step response. Times are in seconds since the step, and are absent if
+/// the response never got there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct StepMetrics {
+    /// Encoder reading before the step
+    pub initial: f32,
+    pub target: f32,
+    /// Time taken to go from 10% to 90% of the step
+    pub rise_time: Option<f32>,
+    /// Furthest the encoder went past the target, as a percentage of the step
+    pub overshoot: f32,
+    /// Time after which the encoder stayed within the settling band
+    pub settling_time: Option<f32>,
+    /// Target minus the mean encoder reading over the last tenth of the samples
+    pub steady_state_error: f32,
+}
+
+impl StepMetrics {
+    /// Compute the metrics for samples taken after stepping from `initial` to `target`
+    pub fn from_samples(initial: f32, target: f32, samples: &[StepSample], band: f32) -> Self {
+        let step = target - initial;
+        // Fraction of the step completed by each sample
+        let progress = |sample: &StepSample| {
+            if step == 0.0 {
+                1.0
+            } else {
+                (sample.encoder - initial) / step
+            }
+        };
+        let first_reaching = |fraction: f32| {
+            samples
+                .iter()
+                .find(|s| progress(s) >= fraction)
+                .map(|s| s.time)
+        };
+        let rise_time = match (first_reaching(0.1), first_reaching(0.9)) {
+            (Some(start), Some(end)) => Some(end - start),
+            _ => None,
+        };
+        let overshoot = samples
+            .iter()
+            .map(|s| (progress(s) - 1.0) * 100.0)
+            .fold(0.0, f32::max);
+
+        let settled = |s: &StepSample| (s.encoder - target).abs() <= band * step.abs();
+        let settling_time = match samples.iter().rposition(|s| !settled(s)) {
+            None => samples.first().map(|s| s.time),
+            Some(last) if last + 1 < samples.len() => Some(samples[last + 1].time),
+            Some(_) => None,
+        };
+
+        let tail = &samples[samples.len() - (samples.len() / 10).max(1).min(samples.len())..];
+        let steady_state_error = if tail.is_empty() {
+            step
+        } else {
+            target - tail.iter().map(|s| s.encoder).sum::<f32>() / tail.len() as f32
+        };
+
+        Self {
+            initial,
+            target,
+            rise_time,
+            overshoot,
+            settling_time,
+            steady_state_error,
+        }
+    }
+}
+
+/// Samples and metrics from a step-response measurement
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct StepResponse {
+    pub metrics: StepMetrics,
+    pub samples: Vec<StepSample>,
+}
+
+impl StepResponse {
+    /// Write the samples as CSV, with a header row
+    pub fn write_csv<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "time,encoder,target,pid_out")?;
+        for s in &self.samples {
+            writeln!(
+                writer,
+                "{},{},{},{}",
+                s.time, s.encoder, s.target, s.pid_out
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Command a step to the configured target and sample the response.
+///
+/// The device should already be enabled and configured with the gains under test.
+pub fn step_response<T: Transport, C: Clock>(
+    aimc: &mut AIMC<T>,
+    clock: &C,
+    config: &StepConfig,
+) -> Result<StepResponse, Error> {
+    let initial = aimc.status()?.encoder;
+    aimc.write_message(AIMCMessage::SetTarget(config.target))?;
+    let start = clock.now();
+
+    let mut samples = Vec::new();
+    loop {
+        let time = clock.now() - start;
+        if time > config.duration {
+            break;
+        }
+        let status = aimc.status()?;
+        samples.push(StepSample {
+            time: time.as_secs_f32(),
+            encoder: status.encoder,
+            target: status.target,
+            pid_out: status.pid_out,
+        });
+        clock.sleep(config.sample_period);
+    }
+
+    Ok(StepResponse {
+        metrics: StepMetrics::from_samples(initial, config.target, &samples, config.settling_band),
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, PlantModel, SimulatedAIMC};
+
+    fn sample(time: f32, encoder: f32) -> StepSample {
+        StepSample {
+            time,
+            encoder,
+            target: 10.0,
+            pid_out: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let samples = [
+            sample(0.0, 0.0),
+            sample(1.0, 2.0),
+            sample(2.0, 9.5),
+            sample(3.0, 12.0),
+            sample(4.0, 10.1),
+            sample(5.0, 9.9),
+            sample(6.0, 10.0),
+        ];
+        let metrics = StepMetrics::from_samples(0.0, 10.0, &samples, 0.02);
+        assert_eq!(metrics.rise_time, Some(1.0));
+        assert!((metrics.overshoot - 20.0).abs() < 1e-4);
+        assert_eq!(metrics.settling_time, Some(4.0));
+        assert_eq!(metrics.steady_state_error, 0.0);
+
+        // Stepping downwards, and never settling
+        let samples = [sample(0.0, 10.0), sample(1.0, 5.0), sample(2.0, 1.0)];
+        let metrics = StepMetrics::from_samples(10.0, 0.0, &samples, 0.02);
+        assert_eq!(metrics.rise_time, Some(1.0));
+        assert_eq!(metrics.overshoot, 0.0);
+        assert_eq!(metrics.settling_time, None);
+        assert_eq!(metrics.steady_state_error, -1.0);
+    }
+
+    #[test]
+    fn test_step_response() {
+        let clock = ManualClock::new();
+        let device = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        let mut aimc = AIMC::from_transport(device);
+        for message in [
+            AIMCMessage::ModePID,
+            AIMCMessage::SetKp(2.0),
+            AIMCMessage::Enable(true),
+        ]
+        .iter()
+        {
+            aimc.write_message(*message).unwrap();
+        }
+
+        let config = StepConfig {
+            target: 100.0,
+            ..Default::default()
+        };
+        let response = step_response(&mut aimc, &clock, &config).unwrap();
+        assert_eq!(response.samples.len(), 501);
+        let metrics = response.metrics;
+        assert!(metrics.rise_time.is_some(), "{:?}", metrics);
+        assert!(metrics.settling_time.is_some(), "{:?}", metrics);
+        assert!(metrics.steady_state_error.abs() < 1.0, "{:?}", metrics);
+
+        let mut csv = Vec::new();
+        response.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 502);
+        assert_eq!(csv.lines().next(), Some("time,encoder,target,pid_out"));
+    }
+}
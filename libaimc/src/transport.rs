@@ -0,0 +1,237 @@
+use crate::{BusLock, Error};
+use i2cdev::linux::LinuxI2CDevice;
+use std::collections::VecDeque;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::{error, fmt, io};
+
+/// Byte-level link between the host and a single AIMC.
+pub trait Transport {
+    /// Write the entire buffer to the device
+    fn write(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// Fill the buffer with bytes read from the device
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error>;
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        (**self).write(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        (**self).read(buffer)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        (**self).write(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        (**self).read(buffer)
+    }
+}
+
+pub(crate) fn nix_to_io(error: nix::Error) -> io::Error {
+    match error {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        other => io::Error::other(other),
+    }
+}
+
+/// Write to an I2C device file already bound to `address`
+pub(crate) fn i2c_write(device: &LinuxI2CDevice, address: u16, data: &[u8]) -> Result<(), Error> {
+    let written = nix::unistd::write(device.as_raw_fd(), data)
+        .map_err(|e| Error::from_io(nix_to_io(e), address))?;
+    if written != data.len() {
+        return Err(Error::ShortWrite {
+            expected: data.len(),
+            actual: written,
+        });
+    }
+    Ok(())
+}
+
+/// Read from an I2C device file already bound to `address`
+pub(crate) fn i2c_read(
+    device: &LinuxI2CDevice,
+    address: u16,
+    buffer: &mut [u8],
+) -> Result<(), Error> {
+    let read = nix::unistd::read(device.as_raw_fd(), buffer)
+        .map_err(|e| Error::from_io(nix_to_io(e), address))?;
+    if read != buffer.len() {
+        return Err(Error::ShortRead {
+            expected: buffer.len(),
+            actual: read,
+        });
+    }
+    Ok(())
+}
+
+/// Linux I2C character device (`/dev/i2c-*`) bound to a single address.
+///
+/// Takes the bus's lock, so only one may be open per bus. To share a bus between devices, use a
+/// `BusManager`.
+pub struct I2CTransport {
+    device: LinuxI2CDevice,
+    address: u16,
+    _lock: BusLock,
+}
+
+impl I2CTransport {
+    /// Open the specified I2C device file and bind it to the address.
+    pub fn open<P: AsRef<Path>>(i2c_device_file: P, address: u16) -> Result<Self, Error> {
+        let path = i2c_device_file.as_ref();
+        let lock = BusLock::acquire(path)?;
+        let device = LinuxI2CDevice::new(path, address).map_err(|e| Error::Open {
+            path: path.to_path_buf(),
+            source: e.into(),
+        })?;
+        Ok(Self {
+            device,
+            address,
+            _lock: lock,
+        })
+    }
+
+    /// Address this transport is bound to
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// Rebind to another address on the same bus, keeping the bus's lock
+    pub fn set_address(&mut self, address: u16) -> Result<(), Error> {
+        self.device
+            .set_slave_address(address)
+            .map_err(|e| Error::from_io(e.into(), address))?;
+        self.address = address;
+        Ok(())
+    }
+}
+
+impl Transport for I2CTransport {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        i2c_write(&self.device, self.address, data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        i2c_read(&self.device, self.address, buffer)
+    }
+}
+
+/// In-memory transport. Records every write and serves reads from a script.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryTransport {
+    writes: Vec<Vec<u8>>,
+    reads: VecDeque<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue bytes to be returned by a future read
+    pub fn queue_read(&mut self, bytes: &[u8]) {
+        self.reads.push_back(bytes.to_vec());
+    }
+
+    /// Every write made so far, oldest first
+    pub fn writes(&self) -> &[Vec<u8>] {
+        &self.writes
+    }
+
+    /// Take the recorded writes, leaving the record empty
+    pub fn take_writes(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.writes)
+    }
+
+    /// Number of queued reads not yet served
+    pub fn pending_reads(&self) -> usize {
+        self.reads.len()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.writes.push(data.to_vec());
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let bytes = self
+            .reads
+            .pop_front()
+            .ok_or_else(|| Error::Transport(Box::new(MemoryTransportError::NoReadQueued)))?;
+        if bytes.len() != buffer.len() {
+            return Err(Error::Transport(Box::new(
+                MemoryTransportError::LengthMismatch {
+                    expected: buffer.len(),
+                    queued: bytes.len(),
+                },
+            )));
+        }
+        buffer.copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryTransportError {
+    /// A read was requested but the script is empty
+    NoReadQueued,
+    /// The next scripted read does not fit the requested buffer
+    LengthMismatch { expected: usize, queued: usize },
+}
+
+impl fmt::Display for MemoryTransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryTransportError::NoReadQueued => write!(f, "No read was queued"),
+            MemoryTransportError::LengthMismatch { expected, queued } => write!(
+                f,
+                "Read of {} bytes requested, but {} bytes were queued",
+                expected, queued
+            ),
+        }
+    }
+}
+
+impl error::Error for MemoryTransportError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_error(result: Result<(), Error>) -> MemoryTransportError {
+        match result {
+            Err(Error::Transport(e)) => e.downcast_ref::<MemoryTransportError>().unwrap().clone(),
+            other => panic!("Expected a memory transport error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scripted_reads() {
+        let mut transport = MemoryTransport::new();
+        transport.queue_read(&[1, 2]);
+        transport.queue_read(&[3]);
+
+        let mut buffer = [0u8; 2];
+        transport.read(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2]);
+        assert_eq!(
+            memory_error(transport.read(&mut buffer)),
+            MemoryTransportError::LengthMismatch {
+                expected: 2,
+                queued: 1
+            }
+        );
+        assert_eq!(
+            memory_error(transport.read(&mut buffer)),
+            MemoryTransportError::NoReadQueued
+        );
+    }
+}
@@ -1,39 +1,194 @@
-use libaimc::AIMCMessage;
+use libaimc::{AIMCMessage, Discovered, HomingConfig, PlantModel, ProtocolVersion};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
 
 /// In-memory representation of AIMC config file
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AIMCConfig {
-    pub address: u16,
-    pub i2c_bus: String,
+    #[serde(flatten)]
+    pub connection: AIMCConnection,
+    #[serde(flatten)]
+    pub setup: AIMCSetup,
+}
+
+impl Default for AIMCConfig {
+    /// Default, just here for example purposes.
+    fn default() -> Self {
+        Self {
+            connection: AIMCConnection::I2C {
+                i2c_bus: "/dev/i2c-0".to_string(),
+                address: 0x00,
+            },
+            setup: AIMCSetup {
+                startup_commands: vec![
+                    AIMCMessage::SetTarget(0.0),
+                    AIMCMessage::Reset,
+                    AIMCMessage::Enable(false),
+                    AIMCMessage::EncoderPolarity(false),
+                    AIMCMessage::LimitPwm(32),
+                    AIMCMessage::ModePWM,
+                    AIMCMessage::ModePneumatic,
+                    AIMCMessage::ModePID,
+                    AIMCMessage::SetKp(0.1),
+                    AIMCMessage::SetKi(0.0),
+                    AIMCMessage::SetKd(0.0),
+                    AIMCMessage::Home(0),
+                    AIMCMessage::LimitTargetMax(0.0),
+                    AIMCMessage::LimitTargetMin(0.0),
+                ],
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// How an AIMC is brought up and driven, whether real or simulated
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AIMCSetup {
+    #[serde(default)]
+    pub protocol: ProtocolVersion,
     pub startup_commands: Vec<AIMCMessage>,
+    /// Read back the device's parameters after startup to check the commands took effect
+    #[serde(default)]
+    pub verify_startup: bool,
+    /// Check the parameters saved in the device's EEPROM against the startup commands instead
+    /// of sending the commands that set them. Commands that set nothing saved are still sent.
+    #[serde(default)]
+    pub startup_from_eeprom: bool,
+    /// Home to the limit switch after the startup commands, before accepting network commands
+    #[serde(default)]
+    pub homing: Option<HomingSettings>,
+    /// Have the device disable itself if no heartbeat arrives for this many milliseconds
+    #[serde(default)]
+    pub watchdog_timeout_ms: Option<u32>,
     #[serde(flatten)]
     pub settings: crate::generic_message::GenericDeviceSettings,
 }
 
-impl Default for AIMCConfig {
+/// Per-device homing parameters
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct HomingSettings {
+    /// Homing speed in PWM units; the sign sets the direction
+    pub speed: i32,
+    /// Give up if the limit switch has not tripped after this many milliseconds
+    pub timeout_ms: u64,
+}
+
+impl HomingSettings {
+    pub fn homing_config(&self) -> HomingConfig {
+        HomingConfig {
+            speed: self.speed,
+            timeout: Duration::from_millis(self.timeout_ms),
+            ..Default::default()
+        }
+    }
+}
+
+/// How to reach an AIMC
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum AIMCConnection {
+    I2C { i2c_bus: String, address: u16 },
+    Serial { serial_port: String, baud: u32 },
+}
+
+/// Config entries for AIMCs found by a bus scan, named after their addresses. They send no
+/// startup commands, leaving each device configured as it was found.
+pub fn discovered_aimcs(i2c_bus: &str, discovered: &[Discovered]) -> BTreeMap<String, AIMCConfig> {
+    discovered
+        .iter()
+        .map(|device| {
+            (
+                format!("aimc_{:#04x}", device.address),
+                AIMCConfig {
+                    connection: AIMCConnection::I2C {
+                        i2c_bus: i2c_bus.to_string(),
+                        address: device.address,
+                    },
+                    setup: AIMCSetup {
+                        startup_commands: vec![],
+                        ..Default::default()
+                    },
+                },
+            )
+        })
+        .collect()
+}
+
+/// In-memory representation of a simulated AIMC's config
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SimulatedAIMCConfig {
+    #[serde(default)]
+    pub plant: PlantModel,
+    #[serde(flatten)]
+    pub setup: AIMCSetup,
+}
+
+impl Default for SimulatedAIMCConfig {
     /// Default, just here for example purposes.
     fn default() -> Self {
         Self {
-            address: 0x00,
-            i2c_bus: "/dev/i2c-0".to_string(),
-            settings: Default::default(),
-            startup_commands: vec![
-                AIMCMessage::SetTarget(0.0),
-                AIMCMessage::Reset,
-                AIMCMessage::Enable(false),
-                AIMCMessage::EncoderPolarity(false),
-                AIMCMessage::LimitPwm(32),
-                AIMCMessage::ModePWM,
-                AIMCMessage::ModePneumatic,
-                AIMCMessage::ModePID,
-                AIMCMessage::SetKp(0.1),
-                AIMCMessage::SetKi(0.0),
-                AIMCMessage::SetKd(0.0),
-                AIMCMessage::Home(0),
-                AIMCMessage::LimitTargetMax(0.0),
-                AIMCMessage::LimitTargetMin(0.0),
-            ],
+            plant: Default::default(),
+            setup: AIMCSetup {
+                startup_commands: vec![
+                    AIMCMessage::ModePID,
+                    AIMCMessage::SetKp(2.0),
+                    AIMCMessage::SetKi(0.0),
+                    AIMCMessage::SetKd(0.0),
+                    AIMCMessage::Enable(true),
+                ],
+                ..Default::default()
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libaimc::{scan, ManualClock, MemoryBus, SimulatedAIMC};
+
+    #[test]
+    fn test_discovered_aimcs() {
+        let mut bus = MemoryBus::new();
+        bus.attach(
+            0x11,
+            SimulatedAIMC::with_clock(PlantModel::default(), ManualClock::new()),
+        );
+        let aimcs = discovered_aimcs("/dev/i2c-1", &scan(&mut bus).unwrap());
+        let yaml = serde_yaml::to_string(&aimcs).unwrap();
+        let parsed: BTreeMap<String, AIMCConfig> = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(
+            parsed["aimc_0x11"].connection,
+            AIMCConnection::I2C {
+                i2c_bus: "/dev/i2c-1".to_string(),
+                address: 0x11
+            }
+        );
+        assert!(parsed["aimc_0x11"].setup.startup_commands.is_empty());
+    }
+
+    #[test]
+    fn test_serial_connection() {
+        let yaml = "
+serial_port: /dev/ttyUSB0
+baud: 115200
+startup_commands: []
+target_mapping:
+  m: 1.0
+  b: 0.0
+";
+        let config: AIMCConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            config.connection,
+            AIMCConnection::Serial {
+                serial_port: "/dev/ttyUSB0".to_string(),
+                baud: 115200
+            }
+        );
+        let parsed: AIMCConfig =
+            serde_yaml::from_str(&serde_yaml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(parsed.connection, config.connection);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::generic_message::GenericMessage;
+use std::time::{Duration, Instant};
+
+/// Notices when clients have gone quiet for too long, so devices can be made safe
+#[derive(Debug)]
+pub struct CommsWatchdog {
+    timeout: Duration,
+    last_heard: Instant,
+    tripped: bool,
+}
+
+impl CommsWatchdog {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            timeout,
+            last_heard: now,
+            tripped: false,
+        }
+    }
+
+    /// Note that the clients were heard from, re-arming the watchdog if it had tripped
+    pub fn feed(&mut self, now: Instant) {
+        self.last_heard = now;
+        self.tripped = false;
+    }
+
+    /// Feed the watchdog if the message shows a driver is still in control. Subscriptions do
+    /// not count, so a dashboard renewing its telemetry lease cannot keep the motors live.
+    pub fn observe(&mut self, message: &GenericMessage, now: Instant) {
+        match message {
+            GenericMessage::Controller(..)
+            | GenericMessage::MessageAll(_)
+            | GenericMessage::Heartbeat => self.feed(now),
+            GenericMessage::Subscribe { .. } => (),
+        }
+    }
+
+    /// Whether the watchdog has just expired. Each silence trips it once.
+    pub fn check(&mut self, now: Instant) -> bool {
+        if self.tripped || now.saturating_duration_since(self.last_heard) < self.timeout {
+            return false;
+        }
+        self.tripped = true;
+        true
+    }
+
+    /// How long until the watchdog expires, or `None` if it already has
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        if self.tripped {
+            None
+        } else {
+            Some((self.last_heard + self.timeout).saturating_duration_since(now))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trips_once() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut watchdog = CommsWatchdog::new(Duration::from_millis(100), start);
+
+        watchdog.feed(ms(50));
+        assert!(!watchdog.check(ms(140)));
+        assert_eq!(watchdog.remaining(ms(140)), Some(Duration::from_millis(10)));
+
+        assert!(watchdog.check(ms(150)));
+        assert!(!watchdog.check(ms(500)));
+        assert_eq!(watchdog.remaining(ms(500)), None);
+
+        // Messages re-arm it
+        watchdog.feed(ms(600));
+        assert!(watchdog.check(ms(700)));
+    }
+
+    #[test]
+    fn test_subscriptions_do_not_feed() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut watchdog = CommsWatchdog::new(Duration::from_millis(100), start);
+
+        watchdog.observe(&GenericMessage::Heartbeat, ms(50));
+        for time in (60..150).step_by(10) {
+            watchdog.observe(&GenericMessage::Subscribe { lease_ms: 1000 }, ms(time));
+        }
+        assert!(watchdog.check(ms(150)));
+    }
+}
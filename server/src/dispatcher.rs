@@ -1,61 +1,414 @@
-use crate::{aimc_config::AIMCConfig, generic_message::*, trace_device::TraceDevice};
-use libaimc::{AIMCMessage, AIMC};
+use crate::{
+    aimc_config::{AIMCConfig, AIMCConnection, AIMCSetup, HomingSettings, SimulatedAIMCConfig},
+    generic_message::*,
+    keepalive_device::KeepaliveDevice,
+    trace_device::TraceDevice,
+};
+use libaimc::{
+    home, load_config, AIMCMessage, BusManager, Capture, CaptureTransport, Clock, DeviceConfig,
+    Parameter, SerialTransport, SimulatedAIMC, StatusFlags, SystemClock, Transport, AIMC,
+};
+use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 /// Command dispatcher. A translation layer between GenericCommands and real devices.
-pub struct Dispatcher(HashMap<String, (Box<dyn GenericDispatch>, GenericDeviceSettings)>);
+#[derive(Default)]
+pub struct Dispatcher {
+    devices: HashMap<String, (Box<dyn GenericDispatch>, GenericDeviceSettings)>,
+    /// When each device publishing telemetry is next due to be read
+    telemetry_due: HashMap<String, Instant>,
+    /// Devices made safe when communication was lost, which only accept `Enable` until enabled
+    held: HashSet<String>,
+}
 
 impl Dispatcher {
     /// Initialize the dispatcher from the specified config struct.
-    pub fn from_config(config: DispatcherConfig) -> Result<Self, Box<Error>> {
-        let mut devices: HashMap<String, (Box<dyn GenericDispatch>, GenericDeviceSettings)> =
-            HashMap::new();
+    pub fn from_config(config: DispatcherConfig) -> Result<Self, Box<dyn Error>> {
+        let mut dispatcher = Self::default();
+        let capture = match &config.capture {
+            Some(path) => {
+                info!("Capturing AIMC traffic to {}", path.display());
+                Some(Capture::create(path)?)
+            }
+            None => None,
+        };
 
+        // AIMCs on the same I2C bus share its handle and take turns on it
+        let buses = BusManager::new();
         for (name, config) in config.aimcs {
-            let mut device = AIMC::new(config.i2c_bus, config.address)?;
-            for command in config.startup_commands {
-                device.write_message(command)?;
-            }
-            devices.insert(name, (Box::new(device), config.settings));
+            let device: Box<dyn GenericDispatch> = match &config.connection {
+                AIMCConnection::I2C { i2c_bus, address } => {
+                    let transport = buses.device(i2c_bus, *address)?;
+                    let transport = captured(transport, &capture, i2c_bus, *address);
+                    start_aimc(&name, AIMC::from_transport(transport), &config.setup)?
+                }
+                AIMCConnection::Serial { serial_port, baud } => {
+                    let transport = SerialTransport::open(serial_port, *baud)?;
+                    let transport = captured(transport, &capture, serial_port, 0);
+                    start_aimc(&name, AIMC::from_transport(transport), &config.setup)?
+                }
+            };
+            dispatcher.add_device(name, device, config.setup.settings);
+        }
+
+        for (name, config) in config.simulated_aimcs {
+            // Each simulated AIMC gets a bus of its own, named after it
+            let transport = captured(SimulatedAIMC::new(config.plant), &capture, &name, 0);
+            let device = start_aimc(&name, AIMC::from_transport(transport), &config.setup)?;
+            dispatcher.add_device(name, device, config.setup.settings);
         }
 
         for name in config.debug_devices {
-            devices.insert(
-                name.clone(),
-                (Box::new(TraceDevice::new(name)), Default::default()),
-            );
+            let device = Box::new(TraceDevice::new(name.clone()));
+            dispatcher.add_device(name, device, Default::default());
         }
 
-        Ok(Self(devices))
+        dispatcher.init()?;
+        Ok(dispatcher)
+    }
+
+    /// Add a device under the name, replacing any device already there
+    pub fn add_device(
+        &mut self,
+        name: String,
+        device: Box<dyn GenericDispatch>,
+        settings: GenericDeviceSettings,
+    ) {
+        self.telemetry_due.remove(&name);
+        self.held.remove(&name);
+        self.devices.insert(name, (device, settings));
     }
 
     /// Dispatch a generic command to the devices
     pub fn dispatch(&mut self, message: GenericMessage) -> Result<(), DispatchError> {
         match message {
+            // Broadcasts go to the devices that support them, and pass the rest by. Held devices
+            // are passed by too, but reported afterwards.
             GenericMessage::MessageAll(command) => {
-                for (device, settings) in self.0.values_mut() {
-                    device
-                        .dispatch(&command, &settings)
-                        .map_err(|e| DispatchError::ControllerFailure(e))?;
+                let mut held = None;
+                for (name, (device, settings)) in self.devices.iter_mut() {
+                    if !device.capabilities(settings).supports(&command) {
+                        trace!("Not sending {:?} to \"{}\"", command.kind(), name);
+                        continue;
+                    }
+                    match dispatch_to(&mut self.held, name, device.as_mut(), &command, settings) {
+                        Err(DispatchError::AwaitingEnable(name)) => {
+                            held.get_or_insert(name);
+                        }
+                        result => result?,
+                    }
                 }
-                Ok(())
+                held.map_or(Ok(()), |name| Err(DispatchError::AwaitingEnable(name)))
             }
-            GenericMessage::Controller(name, command) => match self.0.get_mut(&name) {
-                Some((device, settings)) => device
-                    .dispatch(&command, &settings)
-                    .map_err(|e| DispatchError::ControllerFailure(e)),
+            GenericMessage::Controller(name, command) => match self.devices.get_mut(&name) {
+                Some((device, settings)) => {
+                    dispatch_to(&mut self.held, &name, device.as_mut(), &command, settings)
+                }
                 None => Err(DispatchError::MissingKey(name)),
             },
+            // Subscriptions are kept by the server, which knows who sent them, and heartbeats
+            // only matter to its watchdog
+            GenericMessage::Subscribe { .. } | GenericMessage::Heartbeat => Ok(()),
+        }
+    }
+
+    /// Initialise every device, logging what each can do
+    pub fn init(&mut self) -> Result<(), DispatchError> {
+        for (name, (device, settings)) in self.devices.iter_mut() {
+            device
+                .init(settings)
+                .map_err(|e| DispatchError::ControllerFailure(name.clone(), e))?;
+            info!(
+                "\"{}\" accepts {:?}",
+                name,
+                device.capabilities(settings).commands
+            );
+        }
+        Ok(())
+    }
+
+    /// Make every device safe after communication is lost, by sending its safe action, and hold
+    /// it until it is explicitly enabled again
+    pub fn trip(&mut self) {
+        for (name, (device, settings)) in self.devices.iter_mut() {
+            let action = settings
+                .safe_action
+                .clone()
+                .unwrap_or(GenericCommand::Enable(false));
+            // The safe action goes through even if the device is still held from before
+            self.held.remove(name);
+            match dispatch_to(&mut self.held, name, device.as_mut(), &action, settings) {
+                Ok(()) => info!("Made \"{}\" safe with {:?}", name, action),
+                Err(e) => error!("Could not make \"{}\" safe: {}", name, e),
+            }
+            self.held.insert(name.clone());
+        }
+    }
+
+    /// Shut every device down, logging any that fail to
+    pub fn shutdown(&mut self) {
+        for (name, (device, _)) in self.devices.iter_mut() {
+            match device.shutdown() {
+                Ok(()) => info!("Shut down \"{}\"", name),
+                Err(e) => error!("Could not shut down \"{}\": {}", name, e),
+            }
         }
     }
+
+    /// Read the feedback of every device whose telemetry is due. Devices that fail to report
+    /// are logged and left out.
+    pub fn poll_telemetry(&mut self, now: Instant) -> Vec<Telemetry> {
+        let mut telemetry = Vec::new();
+        for (name, (device, settings)) in self.devices.iter_mut() {
+            let period = match settings.telemetry_period_ms {
+                Some(period_ms) => Duration::from_millis(period_ms),
+                None => continue,
+            };
+            let due = self.telemetry_due.entry(name.clone()).or_insert(now);
+            if *due > now {
+                continue;
+            }
+            // Keep to the schedule, unless so far behind that catching up would flood clients
+            *due = (*due + period).max(now);
+            match device.feedback(settings) {
+                Ok(Some(feedback)) => telemetry.push(Telemetry {
+                    device: name.clone(),
+                    feedback,
+                }),
+                Ok(None) => (),
+                Err(e) => warn!("Could not read feedback from \"{}\": {}", name, e),
+            }
+        }
+        telemetry
+    }
+
+    /// When telemetry is next due from any device, if any device publishes it
+    pub fn next_telemetry(&self, now: Instant) -> Option<Instant> {
+        self.devices
+            .iter()
+            .filter(|(_, (_, settings))| settings.telemetry_period_ms.is_some())
+            .map(|(name, _)| self.telemetry_due.get(name).copied().unwrap_or(now))
+            .min()
+    }
 }
 
+/// Dispatch a command to one device, rejecting it up front if the device does not support it or
+/// is held. Enabling a held device releases it.
+fn dispatch_to(
+    held: &mut HashSet<String>,
+    name: &str,
+    device: &mut dyn GenericDispatch,
+    command: &GenericCommand,
+    settings: &GenericDeviceSettings,
+) -> Result<(), DispatchError> {
+    if !device.capabilities(settings).supports(command) {
+        return Err(DispatchError::Unsupported(name.to_string(), command.kind()));
+    }
+    let enable = match command {
+        GenericCommand::Enable(enable) => Some(*enable),
+        _ => None,
+    };
+    if enable.is_none() && held.contains(name) {
+        return Err(DispatchError::AwaitingEnable(name.to_string()));
+    }
+    device
+        .dispatch(command, settings)
+        .map_err(|e| match e.downcast_ref::<Unsupported>() {
+            Some(unsupported) => DispatchError::Unsupported(name.to_string(), unsupported.0),
+            None => DispatchError::ControllerFailure(name.to_string(), e),
+        })?;
+    if enable == Some(true) && held.remove(name) {
+        info!("Released \"{}\"", name);
+    }
+    Ok(())
+}
+
+/// Record traffic through the transport to the capture, if there is one
+fn captured<T: Transport + Send + 'static>(
+    transport: T,
+    capture: &Option<Capture>,
+    bus: &str,
+    address: u16,
+) -> Box<dyn Transport + Send> {
+    match capture {
+        Some(capture) => Box::new(CaptureTransport::new(
+            transport,
+            capture.clone(),
+            bus,
+            address,
+        )),
+        None => Box::new(transport),
+    }
+}
+
+/// Bring up a configured AIMC, whatever it is connected by
+fn start_aimc<T: Transport + Send + 'static>(
+    name: &str,
+    device: AIMC<T>,
+    config: &AIMCSetup,
+) -> Result<Box<dyn GenericDispatch>, Box<dyn Error>> {
+    let mut device = device.with_protocol(config.protocol);
+    run_startup(
+        name,
+        &mut device,
+        &config.startup_commands,
+        config.verify_startup,
+        config.startup_from_eeprom,
+    )?;
+    if let Some(homing) = &config.homing {
+        run_homing(name, &mut device, homing, &SystemClock::new())?;
+    }
+    match device.status() {
+        Ok(status) => {
+            info!("\"{}\" is up: {}", name, status);
+            if status.flags.contains(StatusFlags::FAULT) {
+                warn!("\"{}\" reports a fault: {}", name, status.flags);
+            }
+        }
+        Err(e) => warn!(
+            "\"{}\" is up, but its status could not be read: {}",
+            name, e
+        ),
+    }
+    into_dispatch(name, device, config.watchdog_timeout_ms)
+}
+
+/// Ready a started AIMC for dispatch, arming its watchdog and keeping it fed if configured to
+fn into_dispatch<T: Transport + Send + 'static>(
+    name: &str,
+    device: AIMC<T>,
+    watchdog_timeout_ms: Option<u32>,
+) -> Result<Box<dyn GenericDispatch>, Box<dyn Error>> {
+    Ok(match watchdog_timeout_ms {
+        Some(timeout_ms) => {
+            info!("Arming watchdog of \"{}\" at {}ms", name, timeout_ms);
+            let timeout = Duration::from_millis(u64::from(timeout_ms));
+            Box::new(KeepaliveDevice::start(name.to_string(), device, timeout)?)
+        }
+        None => Box::new(device),
+    })
+}
+
+/// Send the startup commands to a device, optionally reading its parameters back to check that
+/// they took effect.
+///
+/// With `from_eeprom`, the device's saved parameters are loaded and checked against the commands
+/// that would set them, and only the remaining commands are sent.
+fn run_startup<T: Transport>(
+    name: &str,
+    device: &mut AIMC<T>,
+    commands: &[AIMCMessage],
+    verify: bool,
+    from_eeprom: bool,
+) -> Result<(), Box<dyn Error>> {
+    let saved = |command: &AIMCMessage| {
+        from_eeprom && Parameter::set_by(*command).is_some_and(Parameter::is_persisted)
+    };
+    if from_eeprom {
+        let persisted: Vec<AIMCMessage> = commands.iter().copied().filter(saved).collect();
+        let actual = load_config(device)?;
+        check_config(name, actual, &persisted, true)?;
+        info!("Saved configuration of \"{}\" matches", name);
+    }
+    for command in commands.iter().filter(|command| !saved(command)) {
+        device.write_message(*command)?;
+    }
+    if verify {
+        check_config(name, device.read_config()?, commands, false)?;
+        info!("Verified startup configuration of \"{}\"", name);
+    }
+    Ok(())
+}
+
+/// Check that a device's parameters are as the commands would have left them
+fn check_config(
+    name: &str,
+    actual: DeviceConfig,
+    commands: &[AIMCMessage],
+    saved: bool,
+) -> Result<(), StartupMismatch> {
+    let mut expected = actual;
+    for command in commands {
+        expected.apply(*command);
+    }
+    let parameters = actual.differences(&expected);
+    if parameters.is_empty() {
+        Ok(())
+    } else {
+        Err(StartupMismatch {
+            device: name.to_string(),
+            saved,
+            parameters,
+            expected,
+            actual,
+        })
+    }
+}
+
+/// Home a device to its limit switch, waiting until it gets there
+fn run_homing<T: Transport, C: Clock>(
+    name: &str,
+    device: &mut AIMC<T>,
+    settings: &HomingSettings,
+    clock: &C,
+) -> Result<(), Box<dyn Error>> {
+    info!("Homing \"{}\" at speed {}", name, settings.speed);
+    let report = home(device, clock, &settings.homing_config())?;
+    info!(
+        "Homed \"{}\" in {:?}; flags {}",
+        name, report.elapsed, report.status.flags
+    );
+    Ok(())
+}
+
+/// A device's parameters did not match its startup commands after they were sent, or before
+/// they were sent when checking its saved configuration
+#[derive(Debug)]
+pub struct StartupMismatch {
+    pub device: String,
+    /// Whether it was the saved configuration that did not match
+    pub saved: bool,
+    pub parameters: Vec<Parameter>,
+    pub expected: DeviceConfig,
+    pub actual: DeviceConfig,
+}
+
+impl fmt::Display for StartupMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.saved {
+            write!(f, "Saved configuration of \"{}\" differs:", self.device)?;
+        } else {
+            write!(f, "Device \"{}\" did not accept", self.device)?;
+        }
+        for parameter in &self.parameters {
+            write!(
+                f,
+                " {:?} (expected {:?}, read {:?})",
+                parameter,
+                self.expected.get(*parameter),
+                self.actual.get(*parameter)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for StartupMismatch {}
+
 #[derive(Serialize, Deserialize)]
 pub struct DispatcherConfig {
     pub debug_devices: Vec<String>,
     pub aimcs: HashMap<String, AIMCConfig>,
+    #[serde(default)]
+    pub simulated_aimcs: HashMap<String, SimulatedAIMCConfig>,
+    /// Record all AIMC traffic to this file, for replaying later
+    #[serde(default)]
+    pub capture: Option<PathBuf>,
 }
 
 impl Default for DispatcherConfig {
@@ -65,7 +418,12 @@ impl Default for DispatcherConfig {
                 .iter()
                 .cloned()
                 .collect(),
+            simulated_aimcs: [("simulated".to_string(), SimulatedAIMCConfig::default())]
+                .iter()
+                .cloned()
+                .collect(),
             debug_devices: vec!["debug".to_string()],
+            capture: None,
         }
     }
 }
@@ -73,21 +431,565 @@ impl Default for DispatcherConfig {
 #[derive(Debug)]
 pub enum DispatchError {
     MissingKey(String),
-    ControllerFailure(Box<Error>),
+    /// The named device does not support this kind of command
+    Unsupported(String, CommandKind),
+    /// The named device was made safe when communication was lost, and must be enabled before
+    /// it takes other commands
+    AwaitingEnable(String),
+    /// The named device failed to carry out the command
+    ControllerFailure(String, Box<dyn Error>),
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::MissingKey(name) => write!(f, "No device named \"{}\"", name),
+            DispatchError::Unsupported(name, command) => {
+                write!(f, "\"{}\" does not support {:?}", name, command)
+            }
+            DispatchError::AwaitingEnable(name) => write!(
+                f,
+                "\"{}\" was made safe when communication was lost; enable it first",
+                name
+            ),
+            DispatchError::ControllerFailure(name, e) => write!(f, "\"{}\" failed: {}", name, e),
+        }
+    }
+}
+
+impl Error for DispatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DispatchError::ControllerFailure(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
 }
 
-impl GenericDispatch for AIMC {
+impl DispatchError {
+    /// Whether the failure was a bus glitch that may not recur, as opposed to a missing or
+    /// misbehaving device.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DispatchError::ControllerFailure(_, e) => e
+                .downcast_ref::<libaimc::Error>()
+                .is_some_and(libaimc::Error::is_transient),
+            DispatchError::MissingKey(_)
+            | DispatchError::Unsupported(..)
+            | DispatchError::AwaitingEnable(_) => false,
+        }
+    }
+
+    /// The error to report to a client that asked for a reply
+    pub fn reply_error(&self) -> ReplyError {
+        match self {
+            DispatchError::MissingKey(name) => ReplyError::UnknownDevice(name.clone()),
+            DispatchError::Unsupported(name, command) => ReplyError::Unsupported {
+                device: name.clone(),
+                command: *command,
+            },
+            DispatchError::AwaitingEnable(name) => ReplyError::AwaitingEnable(name.clone()),
+            DispatchError::ControllerFailure(name, e) => ReplyError::ControllerFailure {
+                device: name.clone(),
+                error: e.to_string(),
+                transient: self.is_transient(),
+            },
+        }
+    }
+}
+
+impl<T: Transport> GenericDispatch for AIMC<T> {
+    /// Nothing left to do; `start_aimc` has already sent the startup commands and checked the
+    /// device's status.
+    fn init(&mut self, _: &GenericDeviceSettings) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     fn dispatch(
         &mut self,
         command: &GenericCommand,
         settings: &GenericDeviceSettings,
-    ) -> Result<(), Box<Error>> {
-        self.write_message(match *command {
-            GenericCommand::Enable(enable) => AIMCMessage::Enable(enable),
-            GenericCommand::SetTarget(target) => {
-                AIMCMessage::SetTarget(settings.target_mapping.map(target))
+    ) -> Result<(), Box<dyn Error>> {
+        let mapping = &settings.target_mapping;
+        let messages = match *command {
+            GenericCommand::Enable(enable) => vec![AIMCMessage::Enable(enable)],
+            GenericCommand::SetTarget(target) => vec![AIMCMessage::SetTarget(mapping.map(target))],
+            GenericCommand::Home(speed) => vec![AIMCMessage::Home(speed)],
+            GenericCommand::SetGains { kp, ki, kd } => vec![
+                AIMCMessage::SetKp(kp),
+                AIMCMessage::SetKi(ki),
+                AIMCMessage::SetKd(kd),
+            ],
+            GenericCommand::SetMode(mode) => vec![mode.message()],
+            GenericCommand::SetLimits { min, max } => {
+                // A negative scale swaps which end is which
+                let (a, b) = (mapping.map(min), mapping.map(max));
+                vec![
+                    AIMCMessage::LimitTargetMin(a.min(b)),
+                    AIMCMessage::LimitTargetMax(a.max(b)),
+                ]
             }
-        })
-        .map_err(|e| Box::new(e) as _) //TODO: Remove the as _ when the compiler updates >_>
+            GenericCommand::LimitOutput(limit) => vec![AIMCMessage::LimitPwm(limit)],
+            GenericCommand::Reset => vec![AIMCMessage::Reset],
+            // Entering the bootloader takes the device off its address, and a parameter read
+            // leaves a reply in place of the next status, which the server would misread
+            GenericCommand::Raw(AIMCMessage::EnterBootloader)
+            | GenericCommand::Raw(AIMCMessage::ReadParameter(_)) => {
+                return Err(Box::new(Unsupported(CommandKind::Raw)))
+            }
+            GenericCommand::Raw(message) if settings.allow_raw => vec![message],
+            GenericCommand::Raw(_) => return Err(Box::new(Unsupported(CommandKind::Raw))),
+        };
+        for message in messages {
+            self.write_message(message)?;
+        }
+        Ok(())
+    }
+
+    fn feedback(
+        &mut self,
+        settings: &GenericDeviceSettings,
+    ) -> Result<Option<Feedback>, Box<dyn Error>> {
+        let status = self.status()?;
+        let mapping = &settings.target_mapping;
+        Ok(Some(Feedback {
+            position: mapping.unmap(status.encoder),
+            target: mapping.unmap(status.target),
+            output: status.pid_out,
+            enabled: status.flags.contains(StatusFlags::ENABLED),
+            at_limit: status.flags.at_limit(),
+            fault: status.flags.contains(StatusFlags::FAULT),
+        }))
+    }
+
+    fn capabilities(&self, settings: &GenericDeviceSettings) -> Capabilities {
+        Capabilities::standard(settings, true)
+    }
+
+    fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write_message(AIMCMessage::Enable(false))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linear_mapping::LinearMapping;
+    use libaimc::{
+        parameter_reply_bytes, read_capture, save_config, ControlMode, ManualClock,
+        MemoryTransport, PlantModel, ReplayMismatch, ReplayTransport,
+    };
+
+    #[test]
+    fn test_verified_startup() {
+        let simulated = SimulatedAIMC::with_clock(PlantModel::default(), ManualClock::new());
+        let mut device = AIMC::from_transport(simulated);
+        let commands = SimulatedAIMCConfig::default().setup.startup_commands;
+        run_startup("simulated", &mut device, &commands, true, false).unwrap();
+    }
+
+    #[test]
+    fn test_status_unreadable_at_startup() {
+        // Nothing queued, so the status read fails
+        let device = AIMC::from_transport(MemoryTransport::new());
+        let setup = AIMCSetup {
+            startup_commands: vec![AIMCMessage::Enable(false)],
+            ..Default::default()
+        };
+        let device = start_aimc("mute", device, &setup).unwrap();
+        let mut dispatcher = Dispatcher::default();
+        dispatcher.add_device("mute".to_string(), device, Default::default());
+        dispatcher.init().unwrap();
+    }
+
+    #[test]
+    fn test_startup_from_eeprom() {
+        let clock = ManualClock::new();
+        let simulated = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        let mut device = AIMC::from_transport(simulated);
+        let commands = SimulatedAIMCConfig::default().setup.startup_commands;
+
+        // Nothing saved yet
+        let error = run_startup("fresh", &mut device, &commands, false, true).unwrap_err();
+        let mismatch = error.downcast_ref::<StartupMismatch>().unwrap();
+        assert!(mismatch.saved);
+        assert_eq!(mismatch.parameters, vec![Parameter::Kp]);
+
+        run_startup("fresh", &mut device, &commands, false, false).unwrap();
+        save_config(&mut device, &clock).unwrap();
+        device.transport_mut().power_cycle();
+
+        // The saved gains are used, and only enabling is sent
+        run_startup("saved", &mut device, &commands, true, true).unwrap();
+        let config = device.transport().config();
+        assert_eq!(config.kp, 2.0);
+        assert!(config.enabled);
+    }
+
+    #[test]
+    fn test_homing() {
+        let clock = ManualClock::new();
+        let plant = PlantModel {
+            travel_min: -10.0,
+            ..Default::default()
+        };
+        let mut device = AIMC::from_transport(SimulatedAIMC::with_clock(plant, clock.clone()));
+        let settings = HomingSettings {
+            speed: -64,
+            timeout_ms: 1000,
+        };
+        run_homing("simulated", &mut device, &settings, &clock).unwrap();
+        assert!(device.transport().limit_switch());
+
+        let mut device = AIMC::from_transport(SimulatedAIMC::with_clock(
+            PlantModel::default(),
+            clock.clone(),
+        ));
+        let error = run_homing("far", &mut device, &settings, &clock).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<libaimc::HomingError>(),
+            Some(libaimc::HomingError::Timeout { .. })
+        ));
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let clock = ManualClock::new();
+        let mut simulated = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        simulated.handle_message(AIMCMessage::SetTarget(7.0));
+        let mut dispatcher = Dispatcher::default();
+        dispatcher.add_device(
+            "lift".to_string(),
+            Box::new(AIMC::from_transport(simulated)),
+            GenericDeviceSettings {
+                target_mapping: LinearMapping::new(2.0, 1.0),
+                telemetry_period_ms: Some(20),
+                ..Default::default()
+            },
+        );
+        dispatcher.add_device(
+            "debug".to_string(),
+            Box::new(TraceDevice::new("debug".to_string())),
+            Default::default(),
+        );
+
+        let now = Instant::now();
+        assert_eq!(dispatcher.next_telemetry(now), Some(now));
+        let telemetry = dispatcher.poll_telemetry(now);
+        assert_eq!(telemetry.len(), 1);
+        assert_eq!(telemetry[0].device, "lift");
+        assert_eq!(telemetry[0].feedback.target, 3.0);
+        assert!(!telemetry[0].feedback.enabled);
+
+        // Nothing more until the period is up
+        let next = now + Duration::from_millis(20);
+        assert!(dispatcher
+            .poll_telemetry(now + Duration::from_millis(10))
+            .is_empty());
+        assert_eq!(dispatcher.next_telemetry(now), Some(next));
+        assert_eq!(dispatcher.poll_telemetry(next).len(), 1);
+    }
+
+    #[test]
+    fn test_command_translation() {
+        let mut device = AIMC::from_transport(MemoryTransport::new());
+        let mut settings = GenericDeviceSettings {
+            target_mapping: LinearMapping::new(-2.0, 1.0),
+            ..Default::default()
+        };
+        let commands = [
+            GenericCommand::SetGains {
+                kp: 1.0,
+                ki: 0.5,
+                kd: 0.0,
+            },
+            GenericCommand::SetMode(ControlMode::PWM),
+            GenericCommand::SetLimits { min: 0.0, max: 2.0 },
+            GenericCommand::Home(-32),
+        ];
+        for command in commands.iter() {
+            device.dispatch(command, &settings).unwrap();
+        }
+        let expected = [
+            AIMCMessage::SetKp(1.0),
+            AIMCMessage::SetKi(0.5),
+            AIMCMessage::SetKd(0.0),
+            AIMCMessage::ModePWM,
+            AIMCMessage::LimitTargetMin(-3.0),
+            AIMCMessage::LimitTargetMax(1.0),
+            AIMCMessage::Home(-32),
+        ];
+        let writes = device.transport_mut().take_writes();
+        assert_eq!(
+            writes,
+            expected
+                .iter()
+                .map(|message| message.into_bytes().to_vec())
+                .collect::<Vec<_>>()
+        );
+
+        // Raw messages only get through when allowed
+        let raw = GenericCommand::Raw(AIMCMessage::SetWatchdogTimeout(100));
+        assert!(!device.capabilities(&settings).supports(&raw));
+        let error = device.dispatch(&raw, &settings).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<Unsupported>(),
+            Some(&Unsupported(CommandKind::Raw))
+        );
+        settings.allow_raw = true;
+        assert!(device.capabilities(&settings).supports(&raw));
+        device.dispatch(&raw, &settings).unwrap();
+        assert_eq!(device.transport_mut().take_writes().len(), 1);
+
+        // Even then, not messages that would upset the server's use of the device
+        for message in [
+            AIMCMessage::EnterBootloader,
+            AIMCMessage::ReadParameter(Parameter::Kp),
+        ]
+        .iter()
+        {
+            let error = device
+                .dispatch(&GenericCommand::Raw(*message), &settings)
+                .unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<Unsupported>(),
+                Some(&Unsupported(CommandKind::Raw))
+            );
+        }
+        assert!(device.transport_mut().take_writes().is_empty());
+    }
+
+    /// Valve that can only be opened and closed
+    struct Valve(bool);
+
+    impl GenericDispatch for Valve {
+        fn init(&mut self, _: &GenericDeviceSettings) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn dispatch(
+            &mut self,
+            command: &GenericCommand,
+            _: &GenericDeviceSettings,
+        ) -> Result<(), Box<dyn Error>> {
+            match *command {
+                GenericCommand::Enable(open) => self.0 = open,
+                _ => return Err(Box::new(Unsupported(command.kind()))),
+            }
+            Ok(())
+        }
+
+        fn feedback(
+            &mut self,
+            _: &GenericDeviceSettings,
+        ) -> Result<Option<Feedback>, Box<dyn Error>> {
+            Ok(None)
+        }
+
+        fn capabilities(&self, _: &GenericDeviceSettings) -> Capabilities {
+            Capabilities {
+                commands: vec![CommandKind::Enable],
+                feedback: false,
+            }
+        }
+
+        fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+            self.0 = false;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut dispatcher = Dispatcher::default();
+        let simulated = SimulatedAIMC::with_clock(PlantModel::default(), ManualClock::new());
+        dispatcher.add_device(
+            "lift".to_string(),
+            Box::new(AIMC::from_transport(simulated)),
+            Default::default(),
+        );
+        dispatcher.add_device(
+            "valve".to_string(),
+            Box::new(Valve(false)),
+            Default::default(),
+        );
+        dispatcher.init().unwrap();
+
+        let error = dispatcher
+            .dispatch(GenericMessage::Controller(
+                "valve".to_string(),
+                GenericCommand::SetTarget(1.0),
+            ))
+            .unwrap_err();
+        assert_eq!(
+            error.reply_error(),
+            ReplyError::Unsupported {
+                device: "valve".to_string(),
+                command: CommandKind::SetTarget
+            }
+        );
+
+        // Broadcasts pass the valve by
+        dispatcher
+            .dispatch(GenericMessage::MessageAll(GenericCommand::SetTarget(1.0)))
+            .unwrap();
+        dispatcher
+            .dispatch(GenericMessage::MessageAll(GenericCommand::Enable(true)))
+            .unwrap();
+        let (lift, settings) = dispatcher.devices.get_mut("lift").unwrap();
+        let feedback = lift.feedback(settings).unwrap().unwrap();
+        assert_eq!(feedback.target, 1.0);
+        assert!(feedback.enabled);
+
+        dispatcher.shutdown();
+        let (lift, settings) = dispatcher.devices.get_mut("lift").unwrap();
+        assert!(!lift.feedback(settings).unwrap().unwrap().enabled);
+    }
+
+    #[test]
+    fn test_trip() {
+        let mut dispatcher = Dispatcher::default();
+        let simulated = SimulatedAIMC::with_clock(PlantModel::default(), ManualClock::new());
+        dispatcher.add_device(
+            "lift".to_string(),
+            Box::new(AIMC::from_transport(simulated)),
+            Default::default(),
+        );
+        dispatcher.add_device(
+            "debug".to_string(),
+            Box::new(TraceDevice::new("debug".to_string())),
+            GenericDeviceSettings {
+                safe_action: Some(GenericCommand::SetTarget(0.0)),
+                ..Default::default()
+            },
+        );
+        let feedback = |dispatcher: &mut Dispatcher, name: &str| {
+            let (device, settings) = dispatcher.devices.get_mut(name).unwrap();
+            device.feedback(settings).unwrap().unwrap()
+        };
+        let to = |name: &str, command| GenericMessage::Controller(name.to_string(), command);
+
+        dispatcher
+            .dispatch(GenericMessage::MessageAll(GenericCommand::Enable(true)))
+            .unwrap();
+        dispatcher
+            .dispatch(GenericMessage::MessageAll(GenericCommand::SetTarget(4.0)))
+            .unwrap();
+        dispatcher.trip();
+        assert!(!feedback(&mut dispatcher, "lift").enabled);
+        assert_eq!(feedback(&mut dispatcher, "debug").target, 0.0);
+
+        // Held until enabled
+        let error = dispatcher
+            .dispatch(GenericMessage::MessageAll(GenericCommand::SetTarget(1.0)))
+            .unwrap_err();
+        assert!(matches!(error, DispatchError::AwaitingEnable(_)));
+        dispatcher
+            .dispatch(to("lift", GenericCommand::Enable(true)))
+            .unwrap();
+        dispatcher
+            .dispatch(to("lift", GenericCommand::SetTarget(2.0)))
+            .unwrap();
+        assert_eq!(
+            dispatcher
+                .dispatch(to("debug", GenericCommand::SetTarget(2.0)))
+                .unwrap_err()
+                .reply_error(),
+            ReplyError::AwaitingEnable("debug".to_string())
+        );
+        assert_eq!(feedback(&mut dispatcher, "lift").target, 2.0);
+        assert_eq!(feedback(&mut dispatcher, "debug").target, 0.0);
+    }
+
+    #[test]
+    fn test_startup_mismatch() {
+        // Device that reports power-on defaults regardless of what it is sent
+        let mut transport = MemoryTransport::new();
+        for parameter in Parameter::ALL.iter() {
+            let value = DeviceConfig::default().get(*parameter);
+            transport.queue_read(&parameter_reply_bytes(*parameter, value));
+        }
+        let mut device = AIMC::from_transport(transport);
+        let error = run_startup(
+            "stuck",
+            &mut device,
+            &[AIMCMessage::SetKp(1.0)],
+            true,
+            false,
+        )
+        .unwrap_err();
+        let mismatch = error.downcast_ref::<StartupMismatch>().unwrap();
+        assert_eq!(mismatch.parameters, vec![Parameter::Kp]);
+        assert_eq!(mismatch.expected.kp, 1.0);
+    }
+
+    /// Two AIMCs on one bus, captured while driven over the network
+    const SESSION: &str = "\
+# aimc capture v1
+0.000000 /dev/i2c-1 0x10 W 0101000000
+0.250000 /dev/i2c-1 0x10 W 020000c03f
+0.500000 /dev/i2c-1 0x10 W 0100000000
+0.500000 /dev/i2c-1 0x11 W 0100000000
+";
+
+    #[test]
+    fn test_replay_session() {
+        let records = read_capture(SESSION.as_bytes()).unwrap();
+        let mut dispatcher = Dispatcher::default();
+        let lift = ReplayTransport::for_device(&records, "/dev/i2c-1", 0x10);
+        let settings = GenericDeviceSettings {
+            target_mapping: LinearMapping::new(3.0, 0.0),
+            ..Default::default()
+        };
+        dispatcher.add_device(
+            "lift".to_string(),
+            Box::new(AIMC::from_transport(lift)),
+            settings,
+        );
+        let claw = ReplayTransport::for_device(&records, "/dev/i2c-1", 0x11);
+        dispatcher.add_device(
+            "claw".to_string(),
+            Box::new(AIMC::from_transport(claw)),
+            Default::default(),
+        );
+
+        let lift = |command| GenericMessage::Controller("lift".to_string(), command);
+        dispatcher
+            .dispatch(lift(GenericCommand::Enable(true)))
+            .unwrap();
+        dispatcher
+            .dispatch(lift(GenericCommand::SetTarget(0.5)))
+            .unwrap();
+        dispatcher
+            .dispatch(GenericMessage::MessageAll(GenericCommand::Enable(false)))
+            .unwrap();
+
+        // The whole recording was used up
+        let error = dispatcher
+            .dispatch(lift(GenericCommand::Enable(true)))
+            .unwrap_err();
+        let mismatch = match &error {
+            DispatchError::ControllerFailure(_, e) => match e.downcast_ref::<libaimc::Error>() {
+                Some(libaimc::Error::Transport(e)) => e.downcast_ref::<ReplayMismatch>(),
+                _ => None,
+            },
+            _ => None,
+        };
+        assert_eq!(mismatch.unwrap().expected, None, "{:?}", error);
+        assert!(matches!(
+            error.reply_error(),
+            ReplyError::ControllerFailure { device, transient: false, .. } if device == "lift"
+        ));
+        assert_eq!(
+            dispatcher
+                .dispatch(GenericMessage::Controller(
+                    "wrist".to_string(),
+                    GenericCommand::Enable(true)
+                ))
+                .unwrap_err()
+                .reply_error(),
+            ReplyError::UnknownDevice("wrist".to_string())
+        );
     }
 }
@@ -1,36 +1,274 @@
+use libaimc::{AIMCMessage, ControlMode};
 use serde::{Deserialize, Serialize};
 
+use std::convert::TryFrom;
 use std::error::Error;
+use std::fmt;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum GenericCommand {
     SetTarget(f32),
     Enable(bool),
+    /// Drive towards the limit switch at this speed, in PWM units, or stop homing if zero
+    Home(i32),
+    /// Control loop gains, in device units
+    SetGains {
+        kp: f32,
+        ki: f32,
+        kd: f32,
+    },
+    SetMode(ControlMode),
+    /// Keep the target within these bounds, in client units. Equal bounds remove the limit.
+    SetLimits {
+        min: f32,
+        max: f32,
+    },
+    /// Limit the control loop's output, in PWM units
+    LimitOutput(u8),
+    /// Zero the position and clear the control loop's state
+    Reset,
+    /// Pass a message straight to an AIMC. Only devices configured with `allow_raw` accept it.
+    Raw(AIMCMessage),
+}
+
+/// Kind of a `GenericCommand`, without its arguments
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandKind {
+    SetTarget,
+    Enable,
+    Home,
+    SetGains,
+    SetMode,
+    SetLimits,
+    LimitOutput,
+    Reset,
+    Raw,
+}
+
+impl CommandKind {
+    /// Every kind of command but `Raw`
+    pub const STANDARD: [CommandKind; 8] = [
+        CommandKind::SetTarget,
+        CommandKind::Enable,
+        CommandKind::Home,
+        CommandKind::SetGains,
+        CommandKind::SetMode,
+        CommandKind::SetLimits,
+        CommandKind::LimitOutput,
+        CommandKind::Reset,
+    ];
+}
+
+impl GenericCommand {
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            GenericCommand::SetTarget(_) => CommandKind::SetTarget,
+            GenericCommand::Enable(_) => CommandKind::Enable,
+            GenericCommand::Home(_) => CommandKind::Home,
+            GenericCommand::SetGains { .. } => CommandKind::SetGains,
+            GenericCommand::SetMode(_) => CommandKind::SetMode,
+            GenericCommand::SetLimits { .. } => CommandKind::SetLimits,
+            GenericCommand::LimitOutput(_) => CommandKind::LimitOutput,
+            GenericCommand::Reset => CommandKind::Reset,
+            GenericCommand::Raw(_) => CommandKind::Raw,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum GenericMessage {
     Controller(String, GenericCommand),
     MessageAll(GenericCommand),
+    /// Send the sender telemetry from every device that publishes it, until the lease runs
+    /// out. Subscribe again before then to keep it coming.
+    Subscribe {
+        lease_ms: u32,
+    },
+    /// Do nothing but keep the server's communication watchdog from tripping
+    Heartbeat,
+}
+
+/// A message tagged with an id, asking the server to send a `Reply` back to the sender.
+/// Messages sent untagged get no reply.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TaggedMessage {
+    pub id: u32,
+    pub message: GenericMessage,
+}
+
+/// Outcome of a tagged message, sent back to the address it came from
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub id: u32,
+    pub result: Result<(), ReplyError>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum ReplyError {
+    /// The message was tagged, but what it carried was not a valid message
+    Parse(String),
+    /// No device has this name
+    UnknownDevice(String),
+    /// The device does not support this kind of command
+    Unsupported {
+        device: String,
+        command: CommandKind,
+    },
+    /// The device was made safe when communication was lost, and must be enabled first
+    AwaitingEnable(String),
+    /// The device failed to carry out the command
+    ControllerFailure {
+        device: String,
+        error: String,
+        /// Whether the failure was a bus glitch, so the message may succeed if sent again
+        transient: bool,
+    },
+}
+
+/// Parse a datagram holding either a tagged or an untagged message. The id is returned whenever
+/// the datagram was tagged, even if its message did not parse, so the error can be replied to.
+pub fn parse_message(bytes: &[u8]) -> (Option<u32>, Result<GenericMessage, serde_json::Error>) {
+    let mut value: serde_json::Value = match serde_json::from_slice(bytes) {
+        Ok(value) => value,
+        Err(e) => return (None, Err(e)),
+    };
+    let id = value
+        .get("id")
+        .and_then(serde_json::Value::as_u64)
+        .and_then(|id| u32::try_from(id).ok());
+    match id {
+        Some(id) => {
+            let message = value
+                .get_mut("message")
+                .map(serde_json::Value::take)
+                .unwrap_or_default();
+            (Some(id), serde_json::from_value(message))
+        }
+        None => (None, serde_json::from_value(value)),
+    }
 }
 
 pub trait GenericDispatch {
+    /// Prepare the device for commands. Called once, before any are dispatched.
+    fn init(&mut self, settings: &GenericDeviceSettings) -> Result<(), Box<dyn Error>>;
+
+    /// Carry out a command. Commands missing from the device's capabilities are rejected with
+    /// `Unsupported`.
     fn dispatch(
         &mut self,
         command: &GenericCommand,
         settings: &GenericDeviceSettings,
-    ) -> Result<(), Box<Error>>;
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Read the device's current state, in client units. Devices without feedback return `None`.
+    fn feedback(
+        &mut self,
+        settings: &GenericDeviceSettings,
+    ) -> Result<Option<Feedback>, Box<dyn Error>>;
+
+    fn capabilities(&self, settings: &GenericDeviceSettings) -> Capabilities;
+
+    /// Leave the device safe, as the server is about to stop. No commands follow.
+    fn shutdown(&mut self) -> Result<(), Box<dyn Error>>;
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+/// What a device can do
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Commands the device accepts
+    pub commands: Vec<CommandKind>,
+    /// Whether `feedback` reports the device's state
+    pub feedback: bool,
+}
+
+impl Capabilities {
+    /// The standard commands, and `Raw` if the settings allow it
+    pub fn standard(settings: &GenericDeviceSettings, feedback: bool) -> Self {
+        let mut commands = CommandKind::STANDARD.to_vec();
+        if settings.allow_raw {
+            commands.push(CommandKind::Raw);
+        }
+        Self { commands, feedback }
+    }
+
+    pub fn supports(&self, command: &GenericCommand) -> bool {
+        self.commands.contains(&command.kind())
+    }
+}
+
+/// A device was sent a command it does not support
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unsupported(pub CommandKind);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not supported by this device", self.0)
+    }
+}
+
+impl Error for Unsupported {}
+
+/// State of a device, with positions mapped back into client units
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Feedback {
+    pub position: f32,
+    pub target: f32,
+    /// Output of the control loop, in PWM units
+    pub output: f32,
+    pub enabled: bool,
+    pub at_limit: bool,
+    pub fault: bool,
+}
+
+/// Feedback from one device, published to subscribed clients
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Telemetry {
+    pub device: String,
+    pub feedback: Feedback,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct GenericDeviceSettings {
     pub target_mapping: crate::linear_mapping::LinearMapping,
+    /// Read the device's state this often and publish it to subscribers
+    #[serde(default)]
+    pub telemetry_period_ms: Option<u64>,
+    /// Accept `Raw` commands, which bypass the mapping and can reach anything the device does
+    #[serde(default)]
+    pub allow_raw: bool,
+    /// Sent when the server's communication watchdog trips, instead of `Enable(false)`
+    #[serde(default)]
+    pub safe_action: Option<GenericCommand>,
 }
 
-impl Default for GenericDeviceSettings {
-    fn default() -> Self {
-        Self {
-            target_mapping: Default::default(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let (id, message) = parse_message(br#"{"Controller":["lift",{"SetTarget":1.5}]}"#);
+        assert_eq!(id, None);
+        assert!(matches!(
+            message,
+            Ok(GenericMessage::Controller(name, GenericCommand::SetTarget(_))) if name == "lift"
+        ));
+
+        let tagged = TaggedMessage {
+            id: 7,
+            message: GenericMessage::MessageAll(GenericCommand::Enable(false)),
+        };
+        let (id, message) = parse_message(serde_json::to_string(&tagged).unwrap().as_bytes());
+        assert_eq!(id, Some(7));
+        assert!(matches!(
+            message,
+            Ok(GenericMessage::MessageAll(GenericCommand::Enable(false)))
+        ));
+
+        // A tagged message that does not parse can still be answered
+        let (id, message) = parse_message(br#"{"id":8,"message":{"Jump":true}}"#);
+        assert_eq!(id, Some(8));
+        assert!(message.is_err());
+        assert_eq!(parse_message(b"{\"id\":9").0, None);
     }
 }
@@ -0,0 +1,136 @@
+use crate::generic_message::{
+    Capabilities, Feedback, GenericCommand, GenericDeviceSettings, GenericDispatch,
+};
+use libaimc::{Keepalive, Transport, AIMC};
+use log::warn;
+use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// AIMC with its watchdog armed, fed by a background keepalive regardless of network traffic.
+pub struct KeepaliveDevice<T> {
+    name: String,
+    device: Arc<Mutex<AIMC<T>>>,
+    keepalive: Keepalive,
+}
+
+impl<T: Transport + Send + 'static> KeepaliveDevice<T> {
+    pub fn start(name: String, device: AIMC<T>, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        let device = Arc::new(Mutex::new(device));
+        let keepalive = Keepalive::start(device.clone(), timeout)?;
+        Ok(Self {
+            name,
+            device,
+            keepalive,
+        })
+    }
+}
+
+impl<T: Transport> KeepaliveDevice<T> {
+    fn device(&self) -> MutexGuard<'_, AIMC<T>> {
+        self.device.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T: Transport> GenericDispatch for KeepaliveDevice<T> {
+    fn init(&mut self, settings: &GenericDeviceSettings) -> Result<(), Box<dyn Error>> {
+        self.device().init(settings)
+    }
+
+    fn dispatch(
+        &mut self,
+        command: &GenericCommand,
+        settings: &GenericDeviceSettings,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(e) = self.keepalive.take_error() {
+            warn!("Heartbeat to \"{}\" failed: {}", self.name, e);
+        }
+        self.device().dispatch(command, settings)
+    }
+
+    fn feedback(
+        &mut self,
+        settings: &GenericDeviceSettings,
+    ) -> Result<Option<Feedback>, Box<dyn Error>> {
+        self.device().feedback(settings)
+    }
+
+    fn capabilities(&self, settings: &GenericDeviceSettings) -> Capabilities {
+        self.device().capabilities(settings)
+    }
+
+    /// Disable the device. Heartbeats continue until this is dropped, which does no harm.
+    fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.device().shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libaimc::{AIMCMessage, ManualClock, PlantModel, SimulatedAIMC};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    /// Counts the heartbeats passing through to the device
+    struct Counting<T>(T, Arc<AtomicUsize>);
+
+    impl<T: Transport> Transport for Counting<T> {
+        fn write(&mut self, data: &[u8]) -> Result<(), libaimc::Error> {
+            if *data == AIMCMessage::Heartbeat.into_bytes()[..] {
+                self.1.fetch_add(1, Ordering::SeqCst);
+            }
+            self.0.write(data)
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<(), libaimc::Error> {
+            self.0.read(buffer)
+        }
+    }
+
+    /// Block until the count passes `seen`, however slowly the keepalive thread is scheduled
+    fn wait_past(count: &AtomicUsize, seen: usize) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let now = count.load(Ordering::SeqCst);
+            if now > seen {
+                return now;
+            }
+            assert!(Instant::now() < deadline, "No heartbeat sent");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_keepalive_device() {
+        let clock = ManualClock::new();
+        let heartbeats = Arc::new(AtomicUsize::new(0));
+        let simulated = SimulatedAIMC::with_clock(PlantModel::default(), clock.clone());
+        let mut device = KeepaliveDevice::start(
+            "simulated".to_string(),
+            AIMC::from_transport(Counting(simulated, heartbeats.clone())),
+            Duration::from_millis(100),
+        )
+        .unwrap();
+        device
+            .dispatch(&GenericCommand::Enable(true), &Default::default())
+            .unwrap();
+
+        // Simulated time only moves on once a heartbeat has arrived, so the device never goes
+        // more than 10ms without one
+        let mut seen = 0;
+        for _ in 0..20 {
+            seen = wait_past(&heartbeats, seen);
+            clock.advance(Duration::from_millis(10));
+        }
+        let aimc = device.device.clone();
+        aimc.lock().unwrap().status().unwrap();
+        assert!(aimc.lock().unwrap().transport().0.config().enabled);
+
+        // Once the host goes away, so does the device
+        drop(device);
+        clock.advance(Duration::from_millis(200));
+        aimc.lock().unwrap().status().unwrap();
+        assert!(!aimc.lock().unwrap().transport().0.config().enabled);
+    }
+}
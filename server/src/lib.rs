@@ -3,3 +3,6 @@ pub mod generic_message;
 pub mod trace_device;
 pub mod aimc_config;
 pub mod linear_mapping;
+pub mod keepalive_device;
+pub mod telemetry;
+pub mod comms_watchdog;
@@ -13,6 +13,11 @@ impl LinearMapping {
     pub fn map(&self, value: f32) -> f32 {
         (value * self.m) + self.b
     }
+
+    /// Inverse of `map`, taking device units back to client units. `m` must not be zero.
+    pub fn unmap(&self, value: f32) -> f32 {
+        (value - self.b) / self.m
+    }
 }
 
 impl Default for LinearMapping {
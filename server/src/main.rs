@@ -1,19 +1,32 @@
 use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
+use server::aimc_config::{discovered_aimcs, AIMCConfig};
+use server::comms_watchdog::CommsWatchdog;
 use server::dispatcher::*;
 use server::generic_message::*;
+use server::telemetry::Subscriptions;
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::{ErrorKind, Write},
     net,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 const DEFAULT_CONFIG_DIR: &str = "server.yml";
+const DEFAULT_DISCOVER_BUS: &str = "/dev/i2c-0";
 const MESSAGE_BUFFER_SIZE: usize = 4096;
+const MIN_TIMEOUT: Duration = Duration::from_millis(1);
+const STOP_POLL_PERIOD: Duration = Duration::from_millis(100);
 
 #[derive(Serialize, Deserialize)]
 struct ServerConfig {
     pub socket_address: net::SocketAddr,
+    /// Make every device safe if no valid message arrives for this many milliseconds
+    #[serde(default)]
+    pub comms_timeout_ms: Option<u64>,
     #[serde(flatten)]
     pub dispatcher_config: DispatcherConfig,
 }
@@ -22,11 +35,65 @@ impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             socket_address: "127.0.0.1:5060".parse().unwrap(),
+            comms_timeout_ms: None,
             dispatcher_config: Default::default(),
         }
     }
 }
 
+/// The part of the server config that `--discover` fills in
+#[derive(Serialize)]
+struct DiscoveredConfig {
+    aimcs: BTreeMap<String, AIMCConfig>,
+}
+
+/// Scan the bus and print an `aimcs` config block for every AIMC found
+fn discover(i2c_bus: &str) {
+    let mut bus = match libaimc::I2CBus::open(i2c_bus) {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to open bus for discovery: {}", e);
+            return;
+        }
+    };
+
+    info!("Scanning {} for AIMCs", i2c_bus);
+    match libaimc::scan(&mut bus) {
+        Err(e) => error!("Discovery failed: {}", e),
+        Ok(discovered) => {
+            info!("Found {} AIMC(s)", discovered.len());
+            let config = DiscoveredConfig {
+                aimcs: discovered_aimcs(i2c_bus, &discovered),
+            };
+            print!("{}", serde_yaml::to_string(&config).unwrap());
+        }
+    }
+}
+
+/// Send due telemetry to every subscribed client. Returns how long until more is due, if there
+/// is anyone to send it to.
+fn publish_telemetry(
+    socket: &net::UdpSocket,
+    dispatcher: &mut Dispatcher,
+    subscriptions: &mut Subscriptions,
+) -> Option<Duration> {
+    let now = Instant::now();
+    let clients = subscriptions.active(now);
+    if clients.is_empty() {
+        return None;
+    }
+    for telemetry in dispatcher.poll_telemetry(now) {
+        let bytes = serde_json::to_vec(&telemetry).unwrap();
+        for client in &clients {
+            if let Err(e) = socket.send_to(&bytes, client) {
+                warn!("Could not send telemetry to {}: {}", client, e);
+            }
+        }
+    }
+    let next = dispatcher.next_telemetry(now)?;
+    Some(next.saturating_duration_since(Instant::now()))
+}
+
 fn main() {
     env_logger::Builder::from_default_env()
         .filter(None, log::LevelFilter::Trace)
@@ -36,11 +103,15 @@ fn main() {
     let mut args = std::env::args();
     args.next();
 
-    let config_dir = &args
-        .next()
-        .unwrap_or_else(|| DEFAULT_CONFIG_DIR.to_string());
+    let first_arg = args.next();
+    if first_arg.as_deref() == Some("--discover") {
+        discover(&args.next().unwrap_or_else(|| DEFAULT_DISCOVER_BUS.to_string()));
+        return;
+    }
+
+    let config_dir = &first_arg.unwrap_or_else(|| DEFAULT_CONFIG_DIR.to_string());
 
-    let server_config_file = match File::open(&config_dir) {
+    let server_config_file = match File::open(config_dir) {
         Ok(f) => f,
         Err(e) => {
             match e.kind() {
@@ -85,41 +156,111 @@ fn main() {
         }
     };
 
+    // Shut the devices down on Ctrl-C or SIGTERM, rather than leaving them running
+    let stopping = Arc::new(AtomicBool::new(false));
+    let stop = stopping.clone();
+    if let Err(e) = ctrlc::set_handler(move || stop.store(true, Ordering::SeqCst)) {
+        warn!("Devices will not be shut down on exit: {}", e);
+    }
+
     info!("Starting main loop");
 
+    let mut subscriptions = Subscriptions::default();
+    let mut watchdog = server_config.comms_timeout_ms.map(|timeout_ms| {
+        info!("Communication watchdog at {}ms", timeout_ms);
+        CommsWatchdog::new(Duration::from_millis(timeout_ms), Instant::now())
+    });
+
     'message_loop: loop {
         let mut buf = [0u8; MESSAGE_BUFFER_SIZE];
 
+        if stopping.load(Ordering::SeqCst) {
+            info!("Stopping");
+            dispatcher.shutdown();
+            return;
+        }
+
+        // Make the devices safe if the clients have gone quiet
+        if let Some(watchdog) = &mut watchdog {
+            if watchdog.check(Instant::now()) {
+                warn!("No messages received; making devices safe until they are re-enabled");
+                dispatcher.trip();
+            }
+        }
+
+        // Publish any telemetry that is due, and wake in time for the next, for the watchdog,
+        // or to check whether to stop. A zero timeout would block forever.
+        let timeout = [
+            publish_telemetry(&socket_receiver, &mut dispatcher, &mut subscriptions),
+            watchdog
+                .as_ref()
+                .and_then(|watchdog| watchdog.remaining(Instant::now())),
+            Some(STOP_POLL_PERIOD),
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .min()
+        .unwrap_or(STOP_POLL_PERIOD)
+        .max(MIN_TIMEOUT);
+        if let Err(e) = socket_receiver.set_read_timeout(Some(timeout)) {
+            error!("Socket: {}", e);
+        }
+
         // Receive the message or error and continue
-        let message_bytes = match socket_receiver.recv(&mut buf) {
+        let (message_bytes, sender) = match socket_receiver.recv_from(&mut buf) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue 'message_loop;
+            }
             Err(e) => {
                 error!("Socket: {}", e);
                 continue 'message_loop;
             }
-            Ok(n) => {
+            Ok((n, sender)) => {
                 if n == MESSAGE_BUFFER_SIZE {
                     warn!("Message was the same size as its buffer. This may suggest that the buffer size needs to be larger.");
                 }
-                &buf[..n]
+                (&buf[..n], sender)
             }
         };
 
-        // Parse the message struct or error and continue
-        let message_struct: GenericMessage = match serde_json::from_slice(message_bytes) {
+        // Parse the message struct, or error and reply if the sender asked for a reply
+        let (id, message) = parse_message(message_bytes);
+        if let (Some(watchdog), Ok(message)) = (&mut watchdog, &message) {
+            watchdog.observe(message, Instant::now());
+        }
+        let result = match message {
             Err(e) => {
                 error!("JSON parse error: {}", e);
-                trace!(
-                    "MESSAGE: {:#?}",
-                    String::from_utf8(message_bytes.to_vec()).unwrap()
-                );
-                continue 'message_loop;
+                trace!("MESSAGE: {:#?}", String::from_utf8_lossy(message_bytes));
+                Err(ReplyError::Parse(e.to_string()))
+            }
+            Ok(GenericMessage::Subscribe { lease_ms }) => {
+                let lease = Duration::from_millis(u64::from(lease_ms));
+                subscriptions.subscribe(sender, lease, Instant::now());
+                Ok(())
             }
-            Ok(v) => v,
+            // Attempt to dispatch the command to the motor controllers
+            Ok(message) => dispatcher.dispatch(message).map_err(|e| {
+                if e.is_transient() {
+                    warn!("Dispatch (transient): {:?}", e);
+                } else {
+                    error!("Dispatch: {:?}", e);
+                }
+                e.reply_error()
+            }),
         };
 
-        // Attempt to dispatch the command to the motor controllers
-        if let Err(e) = dispatcher.dispatch(message_struct) {
-            error!("Dispatch: {:?}", e);
+        if let Some(id) = id {
+            let reply = serde_json::to_vec(&Reply { id, result }).unwrap();
+            if let Err(e) = socket_receiver.send_to(&reply, sender) {
+                warn!("Could not reply to {}: {}", sender, e);
+            }
         }
     }
 }
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Longest lease a client may take, so clients that vanish are soon forgotten
+pub const MAX_LEASE: Duration = Duration::from_secs(10);
+
+/// Clients subscribed to telemetry, and when each subscription runs out
+#[derive(Debug, Default)]
+pub struct Subscriptions(HashMap<SocketAddr, Instant>);
+
+impl Subscriptions {
+    /// Subscribe a client, or renew its subscription, for the lease (at most `MAX_LEASE`)
+    pub fn subscribe(&mut self, client: SocketAddr, lease: Duration, now: Instant) {
+        self.0.insert(client, now + lease.min(MAX_LEASE));
+    }
+
+    /// Forget subscriptions that have run out, returning the clients still subscribed
+    pub fn active(&mut self, now: Instant) -> Vec<SocketAddr> {
+        self.0.retain(|_, expiry| *expiry > now);
+        self.0.keys().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let now = Instant::now();
+        let early: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let late: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.subscribe(early, Duration::from_millis(100), now);
+        subscriptions.subscribe(late, Duration::from_secs(3600), now);
+
+        assert_eq!(subscriptions.active(now).len(), 2);
+        assert_eq!(
+            subscriptions.active(now + Duration::from_millis(100)),
+            vec![late]
+        );
+
+        // Renewing extends the lease, but never beyond the maximum
+        subscriptions.subscribe(
+            late,
+            Duration::from_secs(3600),
+            now + Duration::from_secs(5),
+        );
+        assert_eq!(
+            subscriptions.active(now + Duration::from_secs(14)),
+            vec![late]
+        );
+        assert!(subscriptions
+            .active(now + Duration::from_secs(15))
+            .is_empty());
+        assert!(subscriptions.is_empty());
+    }
+}
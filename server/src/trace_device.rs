@@ -1,24 +1,64 @@
-use crate::generic_message::{GenericCommand, GenericDeviceSettings, GenericDispatch};
+use crate::generic_message::{
+    Capabilities, Feedback, GenericCommand, GenericDeviceSettings, GenericDispatch,
+};
 use log::info;
 use std::error::Error;
 
+/// Device that logs the commands it is sent, and reports having followed them perfectly
 pub struct TraceDevice {
     name: String,
+    target: f32,
+    enabled: bool,
 }
 
 impl TraceDevice {
     pub fn new(name: String) -> Self {
-        Self { name }
+        Self {
+            name,
+            target: 0.0,
+            enabled: false,
+        }
     }
 }
 
 impl GenericDispatch for TraceDevice {
+    fn init(&mut self, _: &GenericDeviceSettings) -> Result<(), Box<dyn Error>> {
+        info!("Trace \"{}\": init", self.name);
+        Ok(())
+    }
+
     fn dispatch(
         &mut self,
         command: &GenericCommand,
         _: &GenericDeviceSettings,
-    ) -> Result<(), Box<Error>> {
+    ) -> Result<(), Box<dyn Error>> {
         info!("Trace \"{}\": {:?}", self.name, command);
+        match *command {
+            GenericCommand::SetTarget(target) => self.target = target,
+            GenericCommand::Enable(enabled) => self.enabled = enabled,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn feedback(&mut self, _: &GenericDeviceSettings) -> Result<Option<Feedback>, Box<dyn Error>> {
+        Ok(Some(Feedback {
+            position: self.target,
+            target: self.target,
+            output: 0.0,
+            enabled: self.enabled,
+            at_limit: false,
+            fault: false,
+        }))
+    }
+
+    fn capabilities(&self, settings: &GenericDeviceSettings) -> Capabilities {
+        Capabilities::standard(settings, true)
+    }
+
+    fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("Trace \"{}\": shutdown", self.name);
+        self.enabled = false;
         Ok(())
     }
 }
@@ -1,5 +1,8 @@
-use server::generic_message::{GenericCommand, GenericMessage};
+use server::generic_message::{GenericCommand, GenericMessage, Reply, TaggedMessage};
 use std::net;
+use std::time::Duration;
+
+const REPLY_TIMEOUT: Duration = Duration::from_millis(100);
 
 fn main() {
     let mut args = std::env::args();
@@ -17,17 +20,38 @@ fn main() {
             }
         },
     };
+    // With --ack, tag each message and wait for the server's reply
+    let ack = args.next().as_deref() == Some("--ack");
     let socket_sender =
         net::UdpSocket::bind("0.0.0.0:0").expect("Server failed to bind UDP socket!");
+    socket_sender
+        .set_read_timeout(Some(REPLY_TIMEOUT))
+        .expect("Failed to set timeout");
     let mut target = 0.0;
+    let mut id = 0;
     loop {
         target += 0.1;
         let message =
             GenericMessage::Controller("bench".to_string(), GenericCommand::SetTarget(target));
-        let message_string = serde_json::to_string(&message).unwrap();
+        let message_string = if ack {
+            id += 1;
+            serde_json::to_string(&TaggedMessage { id, message }).unwrap()
+        } else {
+            serde_json::to_string(&message).unwrap()
+        };
         socket_sender
             .send_to(message_string.as_bytes(), send_address)
             .expect("Failed to send");
+        if ack {
+            let mut buf = [0u8; 1024];
+            match socket_sender.recv(&mut buf) {
+                Ok(n) => match serde_json::from_slice::<Reply>(&buf[..n]) {
+                    Ok(reply) => println!("Reply {}: {:?}", reply.id, reply.result),
+                    Err(e) => eprintln!("Malformed reply: {}", e),
+                },
+                Err(e) => eprintln!("No reply to {}: {}", id, e),
+            }
+        }
         std::thread::sleep(std::time::Duration::from_millis(200));
     }
 }